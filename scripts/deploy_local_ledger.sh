@@ -0,0 +1,56 @@
+#!/usr/bin/env bash
+# Deploy a local ICRC-1/ICRC-2 ledger for testing marketplace payments and point
+# recipe_nft_backend at it. Set IC_VERSION to the dfinity/ic commit whose ledger
+# build you want, e.g. IC_VERSION=<commit> ./scripts/deploy_local_ledger.sh
+#
+# The ledger is a dfx project of its own under ledger/, so a plain `dfx deploy`
+# of the marketplace does not need the downloaded wasm
+
+set -euo pipefail
+
+if [ -z "${IC_VERSION:-}" ]; then
+  echo "IC_VERSION must be set to a dfinity/ic commit hash" >&2
+  exit 1
+fi
+
+LEDGER_DIR=ledger
+LEDGER_DID_PATH=${LEDGER_DID_PATH:-rs/ledger_suite/icrc1/ledger/ledger.did}
+
+mkdir -p "$LEDGER_DIR"
+curl -fsSL -o "$LEDGER_DIR/icrc1_ledger.wasm.gz" \
+  "https://download.dfinity.systems/ic/$IC_VERSION/canisters/ic-icrc1-ledger.wasm.gz"
+curl -fsSL -o "$LEDGER_DIR/icrc1_ledger.did" \
+  "https://raw.githubusercontent.com/dfinity/ic/$IC_VERSION/$LEDGER_DID_PATH"
+cat > "$LEDGER_DIR/dfx.json" <<'JSON'
+{
+  "canisters": {
+    "icrc1_ledger": {
+      "candid": "icrc1_ledger.did",
+      "type": "custom",
+      "wasm": "icrc1_ledger.wasm.gz"
+    }
+  },
+  "version": 1
+}
+JSON
+
+DEFAULT=$(dfx identity get-principal)
+MINTER=${MINTER:-$DEFAULT}
+
+(cd "$LEDGER_DIR" && dfx deploy icrc1_ledger --argument "(variant { Init = record {
+  token_symbol = \"TRCP\";
+  token_name = \"Test Recipe Token\";
+  minting_account = record { owner = principal \"$MINTER\" };
+  transfer_fee = 10_000;
+  metadata = vec {};
+  feature_flags = opt record { icrc2 = true };
+  initial_balances = vec { record { record { owner = principal \"$DEFAULT\" }; 100_000_000_000 } };
+  archive_options = record {
+    num_blocks_to_archive = 1000;
+    trigger_threshold = 2000;
+    controller_id = principal \"$DEFAULT\";
+  };
+}})")
+
+dfx canister call recipe_nft_backend set_ledger_canister \
+  "(principal \"$(cd "$LEDGER_DIR" && dfx canister id icrc1_ledger)\")"
@@ -0,0 +1,384 @@
+use crate::dutch_auction;
+use crate::history::{self, TransactionKind};
+use crate::listing::{self, ListingStatus};
+use crate::settlement::{self, Settlement};
+use crate::{
+    caller_user, credit_balance, debit_balance, fund_user, ledger, marketplace_config, Error,
+    AUCTION_STORAGE, ID_COUNTER, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+use validator::Validate;
+
+// auctions can run for at most 30 days
+pub(crate) const MAX_AUCTION_DURATION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AuctionStatus {
+    Open,
+    Settled,
+    Unsold,
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Bid {
+    user_id: u64,
+    amount: u64,
+    placed_at: u64,
+}
+
+// A time-bounded English auction of a recipe. The highest bid is held in
+// escrow, taken out of the bidder's balance until they are outbid or the
+// auction settles
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Auction {
+    id: u64,
+    recipe_id: u64,
+    seller_id: u64,
+    reserve_price: u64,
+    min_increment: u64,
+    start_time: u64,
+    end_time: u64,
+    highest_bid: Option<Bid>,
+    bid_count: u32,
+    status: AuctionStatus,
+}
+
+impl Auction {
+    // the lowest amount the next bid may offer
+    fn min_bid(&self) -> u64 {
+        match &self.highest_bid {
+            Some(bid) => bid.amount.saturating_add(self.min_increment),
+            None => self.reserve_price,
+        }
+    }
+}
+
+impl Storable for Auction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Auction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct StartAuctionPayload {
+    recipe_id: u64,
+    reserve_price: u64,
+    #[validate(range(min = 1))]
+    min_increment: u64,
+    // nanoseconds since the epoch
+    end_time: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct BidPayload {
+    auction_id: u64,
+    amount: u64,
+}
+
+fn get(auction_id: u64) -> Result<Auction, Error> {
+    AUCTION_STORAGE
+        .with(|s| s.borrow().get(&auction_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("auction of id: {} not found", auction_id),
+        })
+}
+
+fn store(auction: &Auction) {
+    AUCTION_STORAGE.with(|s| s.borrow_mut().insert(auction.id, auction.clone()));
+}
+
+// whether a recipe is currently in an English or dutch auction, such recipes
+// can not change hands outside of the auction
+pub(crate) fn is_auctioned(recipe_id: u64) -> bool {
+    AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .any(|(_, a)| a.recipe_id == recipe_id && a.status == AuctionStatus::Open)
+    }) || dutch_auction::is_auctioned(recipe_id)
+}
+
+// settle the auction once its end time has passed
+fn schedule_settlement(auction: &Auction) {
+    let delay = auction.end_time.saturating_sub(ic_cdk::api::time());
+    let auction_id = auction.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || {
+        if settle(auction_id).is_err() {
+            ic_cdk::println!("Auction {} ended without a sale", auction_id);
+        }
+    });
+}
+
+// timers do not survive upgrades, reschedule settlement of every open auction
+pub(crate) fn reschedule_open_auctions() {
+    let open: Vec<Auction> = AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.status == AuctionStatus::Open)
+            .collect()
+    });
+    for auction in open {
+        schedule_settlement(&auction);
+    }
+}
+
+// start an English auction of one of the caller's recipes. The recipe is
+// delisted from fixed price sale while the auction runs
+#[ic_cdk::update]
+fn start_auction(payload: StartAuctionPayload) -> Result<Auction, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let user = caller_user()?;
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    if recipe.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "You can only auction your own recipes".to_string(),
+        });
+    }
+    if recipe.is_community {
+        return Err(Error::InvalidPayload {
+            msg: "This is a community recipe, you can not auction it".to_string(),
+        });
+    }
+    if is_auctioned(recipe.id) {
+        return Err(Error::InvalidPayload {
+            msg: "This recipe is already being auctioned".to_string(),
+        });
+    }
+    let now = ic_cdk::api::time();
+    if payload.end_time <= now || payload.end_time - now > MAX_AUCTION_DURATION {
+        return Err(Error::InvalidPayload {
+            msg: "Auctions must end in the future and within 30 days".to_string(),
+        });
+    }
+    marketplace_config().check_price(payload.reserve_price)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let auction = Auction {
+        id,
+        recipe_id: recipe.id,
+        seller_id: user.id,
+        reserve_price: payload.reserve_price,
+        min_increment: payload.min_increment,
+        start_time: now,
+        end_time: payload.end_time,
+        highest_bid: None,
+        bid_count: 0,
+        status: AuctionStatus::Open,
+    };
+    store(&auction);
+    listing::close(recipe.id, ListingStatus::Delisted);
+    schedule_settlement(&auction);
+    Ok(auction)
+}
+
+// check that a user may place a bid, returning the auction and how much more
+// of their balance the bid needs. A bidder raising their own high bid only
+// adds the difference
+fn check_bid(user_id: u64, payload: &BidPayload) -> Result<(Auction, u64), Error> {
+    let auction = get(payload.auction_id)?;
+    if auction.status != AuctionStatus::Open || ic_cdk::api::time() >= auction.end_time {
+        return Err(Error::InvalidPayload {
+            msg: "This auction has ended".to_string(),
+        });
+    }
+    if auction.seller_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not bid on your own auction".to_string(),
+        });
+    }
+    if payload.amount < auction.min_bid() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Bids must be at least {}", auction.min_bid()),
+        });
+    }
+    let escrowed = match &auction.highest_bid {
+        Some(bid) if bid.user_id == user_id => bid.amount,
+        _ => 0,
+    };
+    Ok((auction, payload.amount - escrowed))
+}
+
+// place a bid on an open auction, escrowing it from the caller's balance. Any
+// shortfall is pulled from their ledger account first, and the previous high
+// bidder is refunded
+#[ic_cdk::update]
+async fn place_bid(payload: BidPayload) -> Result<Auction, Error> {
+    let user = caller_user()?;
+    let (_, needed) = check_bid(user.id, &payload)?;
+    settlement::check_room(&user)?;
+
+    if user.balance < needed {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = needed - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the bid, the auction or balances may have changed during the ledger call
+    let user = caller_user()?;
+    let (auction, needed) = check_bid(user.id, &payload)?;
+    debit_balance(user.id, needed)?;
+    if let Some(previous) = &auction.highest_bid {
+        if previous.user_id != user.id {
+            refund(auction.recipe_id, previous)?;
+        }
+    }
+    history::record(
+        TransactionKind::Bid,
+        Some(auction.recipe_id),
+        Some(user.id),
+        None,
+        Some(needed),
+    );
+
+    let auction = Auction {
+        highest_bid: Some(Bid {
+            user_id: user.id,
+            amount: payload.amount,
+            placed_at: ic_cdk::api::time(),
+        }),
+        bid_count: auction.bid_count + 1,
+        ..auction
+    };
+    store(&auction);
+    Ok(auction)
+}
+
+// release an escrowed bid back to its bidder
+fn refund(recipe_id: u64, bid: &Bid) -> Result<(), Error> {
+    credit_balance(bid.user_id, bid.amount)?;
+    history::record(
+        TransactionKind::BidRefund,
+        Some(recipe_id),
+        None,
+        Some(bid.user_id),
+        Some(bid.amount),
+    );
+    Ok(())
+}
+
+// cancel an auction that has not received any bids
+#[ic_cdk::update]
+fn cancel_auction(auction_id: u64) -> Result<Auction, Error> {
+    let user = caller_user()?;
+    let auction = get(auction_id)?;
+    if auction.seller_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the seller can cancel this auction".to_string(),
+        });
+    }
+    if auction.status != AuctionStatus::Open || auction.highest_bid.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "Only open auctions without bids can be cancelled".to_string(),
+        });
+    }
+    let auction = Auction {
+        status: AuctionStatus::Cancelled,
+        ..auction
+    };
+    store(&auction);
+    Ok(auction)
+}
+
+// settle an auction whose end time has passed. Normally driven by the timer
+// set when the auction started, but anyone can call it
+#[ic_cdk::update]
+fn settle_auction(auction_id: u64) -> Result<Auction, Error> {
+    settle(auction_id)
+}
+
+// sell the recipe to the highest bidder through the regular purchase
+// settlement. The escrowed bid is released to the winner just before the
+// purchase, so a failed sale leaves them refunded and the recipe unsold
+fn settle(auction_id: u64) -> Result<Auction, Error> {
+    let auction = get(auction_id)?;
+    if auction.status != AuctionStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: "This auction has already ended".to_string(),
+        });
+    }
+    if ic_cdk::api::time() < auction.end_time {
+        return Err(Error::InvalidPayload {
+            msg: "This auction has not ended yet".to_string(),
+        });
+    }
+
+    let bid = match &auction.highest_bid {
+        Some(bid) => bid.clone(),
+        None => {
+            let auction = Auction {
+                status: AuctionStatus::Unsold,
+                ..auction
+            };
+            store(&auction);
+            return Ok(auction);
+        }
+    };
+
+    refund(auction.recipe_id, &bid)?;
+    // close the auction first, the recipe can not be sold while it is open
+    let settled = Auction {
+        status: AuctionStatus::Settled,
+        ..auction.clone()
+    };
+    store(&settled);
+
+    let result = match RECIPE_STORAGE.with(|s| s.borrow().get(&auction.recipe_id)) {
+        Some(recipe) if recipe.user_id == auction.seller_id => {
+            Settlement::purchase(recipe, bid.user_id, bid.amount).apply()
+        }
+        _ => Err(Error::NotFound {
+            msg: "The seller no longer owns this recipe".to_string(),
+        }),
+    };
+    if result.is_err() {
+        store(&Auction {
+            status: AuctionStatus::Unsold,
+            ..auction
+        });
+    }
+    result.map(|_| settled)
+}
+
+#[ic_cdk::query]
+fn get_auction(auction_id: u64) -> Result<Auction, Error> {
+    get(auction_id)
+}
+
+#[ic_cdk::query]
+fn get_open_auctions() -> Vec<Auction> {
+    AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.status == AuctionStatus::Open)
+            .collect()
+    })
+}
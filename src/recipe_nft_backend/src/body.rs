@@ -0,0 +1,297 @@
+use validator::{Validate, ValidationError};
+
+// The private part of a recipe, kept apart from the public record. Copies of a
+// limited edition share the body stored under their edition id
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RecipeBody {
+    #[validate(length(max = 100))]
+    #[validate]
+    pub(crate) ingredients: Vec<Ingredient>,
+    #[validate(length(max = 100))]
+    #[validate]
+    pub(crate) steps: Vec<Step>,
+    #[validate(length(max = 30))]
+    pub(crate) equipment: Vec<String>,
+}
+
+// Shape of bodies written before ingredients and steps were structured,
+// decoded as a fallback and converted on read
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecipeBodyV0 {
+    ingredients: Vec<String>,
+    steps: Vec<String>,
+}
+
+impl From<RecipeBodyV0> for RecipeBody {
+    fn from(body: RecipeBodyV0) -> Self {
+        RecipeBody {
+            ingredients: body
+                .ingredients
+                .iter()
+                .map(|line| parse_ingredient(line).unwrap_or_else(|| Ingredient::named(line)))
+                .collect(),
+            steps: body.steps.into_iter().map(Step::new).collect(),
+            equipment: vec![],
+        }
+    }
+}
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum Unit {
+    Gram,
+    Kilogram,
+    Ounce,
+    Pound,
+    Milliliter,
+    Liter,
+    Teaspoon,
+    Tablespoon,
+    FluidOunce,
+    Cup,
+    Pinch,
+    Piece,
+}
+
+impl Unit {
+    // read a unit as written in free-form recipes
+    fn parse(word: &str) -> Option<Unit> {
+        let unit = match word.trim_end_matches('.').to_lowercase().as_str() {
+            "g" | "gram" | "grams" => Unit::Gram,
+            "kg" | "kilogram" | "kilograms" => Unit::Kilogram,
+            "oz" | "ounce" | "ounces" => Unit::Ounce,
+            "lb" | "lbs" | "pound" | "pounds" => Unit::Pound,
+            "ml" | "milliliter" | "milliliters" | "millilitre" | "millilitres" => Unit::Milliliter,
+            "l" | "liter" | "liters" | "litre" | "litres" => Unit::Liter,
+            "tsp" | "teaspoon" | "teaspoons" => Unit::Teaspoon,
+            "tbsp" | "tablespoon" | "tablespoons" => Unit::Tablespoon,
+            "floz" => Unit::FluidOunce,
+            "cup" | "cups" => Unit::Cup,
+            "pinch" | "pinches" => Unit::Pinch,
+            _ => return None,
+        };
+        Some(unit)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+pub(crate) struct Ingredient {
+    #[validate(length(min = 1, max = 100))]
+    pub(crate) name: String,
+    // None for ingredients used to taste
+    #[validate(custom = "is_finite", range(min = 0.0, max = 100000.0))]
+    pub(crate) quantity: Option<f64>,
+    pub(crate) unit: Option<Unit>,
+    #[validate(length(max = 200))]
+    pub(crate) notes: Option<String>,
+}
+
+impl Ingredient {
+    fn named(name: &str) -> Self {
+        Ingredient {
+            name: name.to_string(),
+            quantity: None,
+            unit: None,
+            notes: None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum TemperatureScale {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+pub(crate) struct Temperature {
+    #[validate(custom = "is_finite")]
+    pub(crate) degrees: f64,
+    pub(crate) scale: TemperatureScale,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+pub(crate) struct Step {
+    #[validate(length(min = 1, max = 1000))]
+    pub(crate) instruction: String,
+    // a week at most
+    #[validate(range(max = 10080))]
+    pub(crate) duration_minutes: Option<u32>,
+    // oven or pan temperature the step is done at
+    #[validate]
+    pub(crate) temperature: Option<Temperature>,
+}
+
+// range checks pass NaN, so numbers read from candid are checked for it first
+fn is_finite(value: f64) -> Result<(), ValidationError> {
+    if !value.is_finite() {
+        return Err(ValidationError::new("not_finite"));
+    }
+    Ok(())
+}
+
+impl Step {
+    fn new(instruction: String) -> Self {
+        Step {
+            instruction,
+            duration_minutes: None,
+            temperature: None,
+        }
+    }
+}
+
+// strip list markers such as "-", "*" or "1." from a line
+fn strip_marker(line: &str) -> &str {
+    let line = line.trim();
+    let line = line.trim_start_matches(['-', '*', '•']).trim_start();
+    match line.split_once(['.', ')']) {
+        Some((number, rest))
+            if !number.is_empty()
+                && number.chars().all(|c| c.is_ascii_digit())
+                && rest.starts_with(' ') =>
+        {
+            rest.trim_start()
+        }
+        _ => line,
+    }
+}
+
+// read quantities such as "2", "1.5" or "1/2"
+fn parse_quantity(word: &str) -> Option<f64> {
+    match word.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: f64 = numerator.parse().ok()?;
+            let denominator: f64 = denominator.parse().ok()?;
+            (denominator != 0.0).then(|| numerator / denominator)
+        }
+        None => word.parse().ok(),
+    }
+}
+
+// read a free-form line like "200 g flour, sifted" as an ingredient, None
+// when the line does not start with a quantity
+fn parse_ingredient(line: &str) -> Option<Ingredient> {
+    let line = strip_marker(line);
+    let (quantity, rest) = line.split_once(' ')?;
+    let quantity = parse_quantity(quantity)?;
+    let rest = rest.trim_start().replacen("fl oz ", "floz ", 1);
+    let rest = rest.as_str();
+    let (unit, name) = match rest.split_once(' ') {
+        Some((word, name)) => match Unit::parse(word) {
+            Some(unit) => (Some(unit), name.trim()),
+            None => (None, rest),
+        },
+        None => (None, rest),
+    };
+    let (name, notes) = match name.split_once(',') {
+        Some((name, notes)) => (name.trim(), Some(notes.trim().to_string())),
+        None => (name, None),
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some(Ingredient {
+        name: name.to_string(),
+        quantity: Some(quantity),
+        unit,
+        notes,
+    })
+}
+
+// build a body from a legacy free-form description: lines starting with a
+// quantity become ingredients and every other line a step
+pub(crate) fn from_description(description: &str) -> RecipeBody {
+    let mut body = RecipeBody::default();
+    for line in description.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match parse_ingredient(line) {
+            Some(ingredient) => body.ingredients.push(ingredient),
+            None => body.steps.push(Step::new(strip_marker(line).to_string())),
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_marker_removes_bullets_and_numbers() {
+        assert_eq!(strip_marker("- 2 eggs"), "2 eggs");
+        assert_eq!(strip_marker("* salt"), "salt");
+        assert_eq!(strip_marker("• pepper"), "pepper");
+        assert_eq!(strip_marker("1. Preheat the oven"), "Preheat the oven");
+        assert_eq!(strip_marker("12) Serve"), "Serve");
+    }
+
+    #[test]
+    fn strip_marker_keeps_quantities() {
+        assert_eq!(strip_marker("1.5 cups flour"), "1.5 cups flour");
+        assert_eq!(strip_marker("  200 g sugar  "), "200 g sugar");
+        assert_eq!(strip_marker("Mix well."), "Mix well.");
+    }
+
+    #[test]
+    fn parse_ingredient_reads_quantity_unit_name_and_notes() {
+        let ingredient = parse_ingredient("200 g flour, sifted").unwrap();
+        assert_eq!(ingredient.name, "flour");
+        assert_eq!(ingredient.quantity, Some(200.0));
+        assert!(ingredient.unit == Some(Unit::Gram));
+        assert_eq!(ingredient.notes.as_deref(), Some("sifted"));
+    }
+
+    #[test]
+    fn parse_ingredient_reads_fractions_and_fluid_ounces() {
+        let ingredient = parse_ingredient("- 1/2 tsp. salt").unwrap();
+        assert_eq!(ingredient.quantity, Some(0.5));
+        assert!(ingredient.unit == Some(Unit::Teaspoon));
+        assert_eq!(ingredient.name, "salt");
+
+        let ingredient = parse_ingredient("2 fl oz milk").unwrap();
+        assert!(ingredient.unit == Some(Unit::FluidOunce));
+        assert_eq!(ingredient.name, "milk");
+    }
+
+    #[test]
+    fn parse_ingredient_without_unit_keeps_the_whole_name() {
+        let ingredient = parse_ingredient("3 large eggs").unwrap();
+        assert_eq!(ingredient.quantity, Some(3.0));
+        assert!(ingredient.unit.is_none());
+        assert_eq!(ingredient.name, "large eggs");
+    }
+
+    #[test]
+    fn parse_ingredient_rejects_lines_without_quantity() {
+        assert!(parse_ingredient("Preheat the oven").is_none());
+        assert!(parse_ingredient("1/0 cup sugar").is_none());
+        assert!(parse_ingredient("salt").is_none());
+    }
+
+    #[test]
+    fn validation_rejects_nan_quantities_and_temperatures() {
+        let ingredient = Ingredient {
+            quantity: Some(f64::NAN),
+            ..Ingredient::named("flour")
+        };
+        assert!(ingredient.validate().is_err());
+        assert!(Ingredient::named("flour").validate().is_ok());
+
+        let step = Step {
+            temperature: Some(Temperature {
+                degrees: f64::NAN,
+                scale: TemperatureScale::Celsius,
+            }),
+            ..Step::new("Bake".to_string())
+        };
+        assert!(step.validate().is_err());
+        let step = Step {
+            temperature: Some(Temperature {
+                degrees: f64::INFINITY,
+                scale: TemperatureScale::Celsius,
+            }),
+            ..Step::new("Bake".to_string())
+        };
+        assert!(step.validate().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::body::{self, RecipeBody, RecipeBodyV0};
+use crate::edition::{self, Edition};
+use crate::encryption::{self, SealedBody, WrappedKey};
+use crate::media::{self, MediaRef};
+use crate::nutrition::Nutrition;
+use crate::{
+    caller_user, rental, subscription, user_by_principal, Error, Recipe, RECIPE_BODY_STORAGE,
+    RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::time::Duration;
+
+// What anyone can see of a recipe
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecipePreview {
+    id: u64,
+    title: String,
+    category: String,
+    teaser: String,
+    image_url: Option<String>,
+    media: Vec<MediaRef>,
+    servings: Option<u32>,
+    prep_minutes: Option<u32>,
+    cook_minutes: Option<u32>,
+    nutrition: Option<Nutrition>,
+    price: u64,
+    user_id: u64,
+    creator_id: Option<u64>,
+    is_community: bool,
+    is_for_sale: bool,
+    edition: Option<Edition>,
+    reviews: Vec<String>,
+}
+
+impl From<Recipe> for RecipePreview {
+    fn from(recipe: Recipe) -> Self {
+        let media = media::refs(&recipe);
+        RecipePreview {
+            image_url: recipe.image_url.or_else(|| media::cover_url(&media)),
+            media,
+            id: recipe.id,
+            title: recipe.title,
+            category: recipe.category,
+            teaser: recipe.teaser.unwrap_or_default(),
+            servings: recipe.servings,
+            prep_minutes: recipe.prep_minutes,
+            cook_minutes: recipe.cook_minutes,
+            nutrition: recipe.nutrition,
+            price: recipe.price,
+            user_id: recipe.user_id,
+            creator_id: recipe.creator_id,
+            is_community: recipe.is_community,
+            is_for_sale: recipe.is_for_sale,
+            edition: recipe.edition,
+            reviews: recipe.reviews,
+        }
+    }
+}
+
+// The whole recipe, for callers allowed to read its body. The ingredients and
+// steps are a candid encoded 'RecipeBody' encrypted under the recipe key,
+// which is only included when it was wrapped for the caller
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecipeContent {
+    preview: RecipePreview,
+    description: String,
+    body: Option<SealedBody>,
+    wrapped_key: Option<WrappedKey>,
+}
+
+// the key a recipe's body and encryption key are stored under
+pub(crate) fn body_key(recipe: &Recipe) -> u64 {
+    match &recipe.edition {
+        Some(edition) => edition.edition_id,
+        None => recipe.id,
+    }
+}
+
+// decrypt a stored body, falling back to the unstructured body shape
+fn read_body(id: u64, sealed: &SealedBody) -> Result<RecipeBody, Error> {
+    let bytes = encryption::open(id, sealed)?;
+    match Decode!(&bytes, RecipeBody) {
+        Ok(body) => Ok(body),
+        Err(_) => Decode!(&bytes, RecipeBodyV0)
+            .map(|body| body.into())
+            .map_err(|e| Error::InvalidPayload { msg: e.to_string() }),
+    }
+}
+
+// decrypt the body of a recipe, recipes without one have an empty body
+pub(crate) fn body_of(recipe: &Recipe) -> Result<RecipeBody, Error> {
+    let id = body_key(recipe);
+    match RECIPE_BODY_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(sealed) => read_body(id, &sealed),
+        None => Ok(RecipeBody::default()),
+    }
+}
+
+// encrypt and store the body of a recipe or edition, rejecting bodies too
+// large for stable memory. The recipe key must have been created first
+pub(crate) fn store_body(id: u64, body: RecipeBody) -> Result<(), Error> {
+    let plaintext = Encode!(&body).map_err(|e| Error::InvalidPayload { msg: e.to_string() })?;
+    let sealed = encryption::seal(id, &plaintext)?;
+    if sealed.to_bytes().len() > SealedBody::MAX_SIZE as usize {
+        return Err(Error::InvalidPayload {
+            msg: "Recipe ingredients and steps are too long".to_string(),
+        });
+    }
+    RECIPE_BODY_STORAGE.with(|s| s.borrow_mut().insert(id, sealed));
+    Ok(())
+}
+
+fn sealed_body_of(recipe: &Recipe) -> Option<SealedBody> {
+    RECIPE_BODY_STORAGE.with(|s| s.borrow().get(&body_key(recipe)))
+}
+
+// the full recipe with its body's key wrapped for a user's public key,
+// 'ephemeral' must be fresh randomness
+pub(crate) fn unlocked_content(
+    recipe: Recipe,
+    public_key: &[u8],
+    ephemeral: [u8; 32],
+) -> Result<RecipeContent, Error> {
+    let wrapped_key = encryption::wrap(body_key(&recipe), public_key, ephemeral)?;
+    Ok(RecipeContent {
+        description: recipe.description.clone(),
+        body: sealed_body_of(&recipe),
+        wrapped_key: Some(wrapped_key),
+        preview: recipe.into(),
+    })
+}
+
+// whether a user may read the body of a recipe: community recipes are open to
+// everyone, others to their owner, creator, renters and the owner's subscribers
+pub(crate) fn can_view(user_id: Option<u64>, recipe: &Recipe) -> bool {
+    if recipe.is_community {
+        return true;
+    }
+    match user_id {
+        Some(user_id) => {
+            recipe.user_id == user_id
+                || recipe.creator_id == Some(user_id)
+                || rental::active_rental(user_id, recipe.id).is_some()
+                || subscription::active_subscription(user_id, recipe.user_id).is_some()
+        }
+        None => false,
+    }
+}
+
+// get a recipe a user may read the body of
+pub(crate) fn viewable_recipe(user_id: Option<u64>, recipe_id: u64) -> Result<Recipe, Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        })?;
+    if !can_view(user_id, &recipe) {
+        return Err(Error::Unauthorized {
+            msg: "Buy, rent or subscribe to read this recipe".to_string(),
+        });
+    }
+    Ok(recipe)
+}
+
+// get the full recipe with its encrypted body, only for callers allowed to
+// read it. Clients that do not hold the recipe key yet get it from unlock_recipe
+#[ic_cdk::query]
+fn get_recipe_content(recipe_id: u64) -> Result<RecipeContent, Error> {
+    let user_id = user_by_principal(ic_cdk::caller()).map(|user| user.id);
+    let recipe = viewable_recipe(user_id, recipe_id)?;
+    Ok(RecipeContent {
+        description: recipe.description.clone(),
+        body: sealed_body_of(&recipe),
+        wrapped_key: None,
+        preview: recipe.into(),
+    })
+}
+
+// get the full recipe with its key wrapped for the caller's public encryption key
+#[ic_cdk::update]
+async fn unlock_recipe(recipe_id: u64) -> Result<RecipeContent, Error> {
+    let ephemeral = encryption::random_bytes().await?;
+    let user = caller_user()?;
+    let public_key = user.encryption_key.ok_or_else(|| Error::InvalidPayload {
+        msg: "Set an encryption key before unlocking recipes".to_string(),
+    })?;
+    let recipe = viewable_recipe(Some(user.id), recipe_id)?;
+    unlocked_content(recipe, &public_key, ephemeral)
+}
+
+// give every recipe and edition created before encryption a key and build
+// bodies for description-only recipes
+pub(crate) async fn migrate_legacy_bodies() {
+    let seed = match encryption::random_bytes().await {
+        Ok(seed) => seed,
+        Err(_) => {
+            ic_cdk::println!("Could not get randomness, retrying recipe encryption");
+            ic_cdk_timers::set_timer(Duration::from_secs(60), || {
+                ic_cdk::spawn(migrate_legacy_bodies())
+            });
+            return;
+        }
+    };
+
+    // the description of every recipe and edition, by body id
+    let mut descriptions: Vec<(u64, String)> = RECIPE_STORAGE.with(|recipes| {
+        recipes
+            .borrow()
+            .iter()
+            .map(|(_, recipe)| (body_key(&recipe), recipe.description))
+            .collect()
+    });
+    descriptions.extend(edition::descriptions());
+    for (id, _) in &descriptions {
+        encryption::create_key(*id, encryption::derive_key(&seed, *id));
+    }
+
+    for (id, description) in descriptions {
+        if RECIPE_BODY_STORAGE.with(|s| s.borrow().contains_key(&id)) {
+            continue;
+        }
+        if store_body(id, body::from_description(&description)).is_err() {
+            ic_cdk::println!("Could not migrate description of recipe id: {}", id);
+        }
+    }
+}
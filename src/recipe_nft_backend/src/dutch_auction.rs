@@ -0,0 +1,310 @@
+use crate::auction::{self, MAX_AUCTION_DURATION};
+use crate::license;
+use crate::listing::{self, ListingStatus};
+use crate::settlement;
+use crate::{
+    caller_user, fund_user, ledger, marketplace_config, transfer_recipe_to_user, Error, Recipe,
+    DUTCH_AUCTION_STORAGE, ID_COUNTER, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum DutchAuctionStatus {
+    Open,
+    Sold,
+    Expired,
+}
+
+// How the price falls from the start price to the floor. 'Linear' decays
+// continuously and reaches the floor at the end time, 'Stepped' drops the price
+// by 'amount' every 'interval' nanoseconds until it reaches the floor
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum DecaySchedule {
+    Linear,
+    Stepped { interval: u64, amount: u64 },
+}
+
+// A descending-price auction of a recipe, the first buyer at the current
+// price wins it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DutchAuction {
+    id: u64,
+    recipe_id: u64,
+    seller_id: u64,
+    start_price: u64,
+    floor_price: u64,
+    decay: DecaySchedule,
+    start_time: u64,
+    end_time: u64,
+    status: DutchAuctionStatus,
+    buyer_id: Option<u64>,
+    sold_price: Option<u64>,
+}
+
+impl DutchAuction {
+    // the asking price at a point in time, never below the floor
+    fn price_at(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.start_time);
+        let range = self.start_price - self.floor_price;
+        let drop = match self.decay {
+            DecaySchedule::Linear => {
+                let duration = self.end_time - self.start_time;
+                (range as u128 * elapsed.min(duration) as u128 / duration as u128) as u64
+            }
+            DecaySchedule::Stepped { interval, amount } => {
+                (elapsed / interval).saturating_mul(amount)
+            }
+        };
+        self.start_price - drop.min(range)
+    }
+}
+
+impl Storable for DutchAuction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DutchAuction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StartDutchAuctionPayload {
+    recipe_id: u64,
+    start_price: u64,
+    floor_price: u64,
+    decay: DecaySchedule,
+    // nanoseconds since the epoch
+    end_time: u64,
+}
+
+// An auction together with its asking price when it was read
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DutchAuctionPrice {
+    auction: DutchAuction,
+    current_price: u64,
+}
+
+fn get(auction_id: u64) -> Result<DutchAuction, Error> {
+    DUTCH_AUCTION_STORAGE
+        .with(|s| s.borrow().get(&auction_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("dutch auction of id: {} not found", auction_id),
+        })
+}
+
+fn store(auction: &DutchAuction) {
+    DUTCH_AUCTION_STORAGE.with(|s| s.borrow_mut().insert(auction.id, auction.clone()));
+}
+
+// whether a recipe has an open dutch auction
+pub(crate) fn is_auctioned(recipe_id: u64) -> bool {
+    DUTCH_AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .any(|(_, a)| a.recipe_id == recipe_id && a.status == DutchAuctionStatus::Open)
+    })
+}
+
+// expire the auction once its end time has passed
+fn schedule_expiry(auction: &DutchAuction) {
+    let delay = auction.end_time.saturating_sub(ic_cdk::api::time());
+    let auction_id = auction.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || expire(auction_id));
+}
+
+// timers do not survive upgrades, reschedule expiry of every open auction
+pub(crate) fn reschedule_open_auctions() {
+    let open: Vec<DutchAuction> = DUTCH_AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.status == DutchAuctionStatus::Open)
+            .collect()
+    });
+    for auction in open {
+        schedule_expiry(&auction);
+    }
+}
+
+// an auction nobody bought reverts its recipe to unlisted
+fn expire(auction_id: u64) {
+    let auction = match get(auction_id) {
+        Ok(auction) if auction.status == DutchAuctionStatus::Open => auction,
+        _ => return,
+    };
+    store(&DutchAuction {
+        status: DutchAuctionStatus::Expired,
+        ..auction.clone()
+    });
+    listing::close(auction.recipe_id, ListingStatus::Delisted);
+}
+
+// start a dutch auction of one of the caller's recipes. The price falls from
+// the start price to the floor following the decay schedule
+#[ic_cdk::update]
+fn start_dutch_auction(payload: StartDutchAuctionPayload) -> Result<DutchAuction, Error> {
+    let user = caller_user()?;
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    if recipe.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "You can only auction your own recipes".to_string(),
+        });
+    }
+    if recipe.is_community {
+        return Err(Error::InvalidPayload {
+            msg: "This is a community recipe, you can not auction it".to_string(),
+        });
+    }
+    if auction::is_auctioned(recipe.id) {
+        return Err(Error::InvalidPayload {
+            msg: "This recipe is already being auctioned".to_string(),
+        });
+    }
+    let now = ic_cdk::api::time();
+    if payload.end_time <= now || payload.end_time - now > MAX_AUCTION_DURATION {
+        return Err(Error::InvalidPayload {
+            msg: "Auctions must end in the future and within 30 days".to_string(),
+        });
+    }
+    if payload.floor_price > payload.start_price {
+        return Err(Error::InvalidPayload {
+            msg: "The floor price can not be above the start price".to_string(),
+        });
+    }
+    if let DecaySchedule::Stepped { interval, amount } = payload.decay {
+        if interval == 0 || amount == 0 {
+            return Err(Error::InvalidPayload {
+                msg: "Price steps need a non-zero interval and amount".to_string(),
+            });
+        }
+    }
+    let config = marketplace_config();
+    config.check_price(payload.start_price)?;
+    config.check_price(payload.floor_price)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let auction = DutchAuction {
+        id,
+        recipe_id: recipe.id,
+        seller_id: user.id,
+        start_price: payload.start_price,
+        floor_price: payload.floor_price,
+        decay: payload.decay,
+        start_time: now,
+        end_time: payload.end_time,
+        status: DutchAuctionStatus::Open,
+        buyer_id: None,
+        sold_price: None,
+    };
+    store(&auction);
+    listing::close(recipe.id, ListingStatus::Delisted);
+    schedule_expiry(&auction);
+    Ok(auction)
+}
+
+// check that a user may buy from an auction, returning it with its current price
+fn check_dutch_purchase(user_id: u64, auction_id: u64) -> Result<(DutchAuction, u64), Error> {
+    let auction = get(auction_id)?;
+    let now = ic_cdk::api::time();
+    if auction.status != DutchAuctionStatus::Open || now >= auction.end_time {
+        return Err(Error::InvalidPayload {
+            msg: "This auction has ended".to_string(),
+        });
+    }
+    if auction.seller_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not buy your own recipe".to_string(),
+        });
+    }
+    let price = auction.price_at(now);
+    Ok((auction, price))
+}
+
+// buy the recipe at the auction's current price. Any shortfall in the caller's
+// balance is pulled from their ledger account first, like 'buy_recipe_nft'
+#[ic_cdk::update]
+async fn buy_dutch_auction(auction_id: u64) -> Result<DutchAuction, Error> {
+    let user = caller_user()?;
+    let (_, price) = check_dutch_purchase(user.id, auction_id)?;
+    settlement::check_room(&user)?;
+
+    if user.balance < price {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = price - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the purchase, the price only falls while the ledger call runs
+    let user = caller_user()?;
+    let (auction, price) = check_dutch_purchase(user.id, auction_id)?;
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&auction.recipe_id))
+        .filter(|recipe| recipe.user_id == auction.seller_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: "The seller no longer owns this recipe".to_string(),
+        })?;
+
+    // close the auction first, the recipe can not be sold while it is open
+    let sold = DutchAuction {
+        status: DutchAuctionStatus::Sold,
+        buyer_id: Some(user.id),
+        sold_price: Some(price),
+        ..auction.clone()
+    };
+    store(&sold);
+    let license = license::license_of(&recipe);
+    if let Err(e) = transfer_recipe_to_user(user.id, Recipe { price, ..recipe }, license) {
+        store(&auction);
+        return Err(e);
+    }
+    Ok(sold)
+}
+
+#[ic_cdk::query]
+fn get_dutch_auction(auction_id: u64) -> Result<DutchAuctionPrice, Error> {
+    let auction = get(auction_id)?;
+    let current_price = auction.price_at(ic_cdk::api::time());
+    Ok(DutchAuctionPrice {
+        auction,
+        current_price,
+    })
+}
+
+#[ic_cdk::query]
+fn get_open_dutch_auctions() -> Vec<DutchAuctionPrice> {
+    let now = ic_cdk::api::time();
+    DUTCH_AUCTION_STORAGE.with(|auctions| {
+        auctions
+            .borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.status == DutchAuctionStatus::Open && now < a.end_time)
+            .map(|auction| DutchAuctionPrice {
+                current_price: auction.price_at(now),
+                auction,
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,320 @@
+use crate::body::RecipeBody;
+use crate::content::{self, RecipeContent, RecipePreview};
+use crate::encryption;
+use crate::history::{self, TransactionKind};
+use crate::license::LicenseTier;
+use crate::nutrition::{self, Nutrition};
+use crate::pages;
+use crate::settlement::{self, Settlement};
+use crate::{
+    add_recipe_to_owner, caller_user, check_size, fund_user, ledger, marketplace_config,
+    user_by_principal, Error, Recipe, EDITION_STORAGE, ID_COUNTER, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use validator::Validate;
+
+// The place of a recipe token in a limited edition
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Edition {
+    pub(crate) edition_id: u64,
+    pub(crate) number: u32,
+    pub(crate) max_supply: u32,
+}
+
+// A recipe a creator sells as up to 'max_supply' copies. Every copy bought
+// from the primary sale is minted as its own recipe token with the next
+// edition number, and the creator stays the author of every copy. The
+// ingredients and steps of every copy are stored once, under the edition id
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct EditionSet {
+    id: u64,
+    creator_id: u64,
+    title: String,
+    category: String,
+    description: String,
+    teaser: Option<String>,
+    image_url: Option<String>,
+    servings: Option<u32>,
+    prep_minutes: Option<u32>,
+    cook_minutes: Option<u32>,
+    nutrition: Option<Nutrition>,
+    price: u64,
+    royalty_bps: u16,
+    max_supply: u32,
+    minted: u32,
+    // whether copies can still be bought from the creator
+    is_open: bool,
+}
+
+impl Storable for EditionSet {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EditionSet {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct EditionPayload {
+    #[validate(length(min = 3))]
+    title: String,
+    category: String,
+    #[validate(length(min = 6))]
+    description: String,
+    #[validate(length(max = 280))]
+    teaser: String,
+    #[validate(url)]
+    image_url: Option<String>,
+    #[validate]
+    body: RecipeBody,
+    #[validate(range(min = 1, max = 100))]
+    servings: u32,
+    #[validate(range(max = 1440))]
+    prep_minutes: u32,
+    #[validate(range(max = 1440))]
+    cook_minutes: u32,
+    price: u64,
+    #[validate(range(min = 1, max = 10000))]
+    max_supply: u32,
+    // at most 50% of secondary sales can go to the creator
+    #[validate(range(max = 5000))]
+    royalty_bps: u16,
+}
+
+fn get(edition_id: u64) -> Result<EditionSet, Error> {
+    EDITION_STORAGE
+        .with(|s| s.borrow().get(&edition_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("edition of id: {} not found", edition_id),
+        })
+}
+
+// the description of every edition, for migrating them to structured bodies
+pub(crate) fn descriptions() -> Vec<(u64, String)> {
+    EDITION_STORAGE.with(|editions| {
+        editions
+            .borrow()
+            .iter()
+            .map(|(id, edition)| (id, edition.description))
+            .collect()
+    })
+}
+
+fn store(edition: &EditionSet) {
+    EDITION_STORAGE.with(|s| s.borrow_mut().insert(edition.id, edition.clone()));
+}
+
+fn next_id() -> u64 {
+    ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids")
+}
+
+// create a limited edition of a recipe authored by the caller
+#[ic_cdk::update]
+async fn create_edition(payload: EditionPayload) -> Result<EditionSet, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let creator = caller_user()?;
+    let config = marketplace_config();
+    config.check_category(&payload.category)?;
+    config.check_price(payload.price)?;
+    let key = encryption::random_bytes().await?;
+
+    let nutrition = nutrition::compute(&payload.body, payload.servings);
+    // every copy is read with the edition's key
+    let id = next_id();
+    let edition = EditionSet {
+        id,
+        creator_id: creator.id,
+        title: payload.title,
+        category: payload.category,
+        description: payload.description,
+        teaser: Some(payload.teaser),
+        image_url: payload.image_url,
+        servings: Some(payload.servings),
+        prep_minutes: Some(payload.prep_minutes),
+        cook_minutes: Some(payload.cook_minutes),
+        nutrition: Some(nutrition),
+        price: payload.price,
+        royalty_bps: payload.royalty_bps,
+        max_supply: payload.max_supply,
+        minted: 0,
+        is_open: true,
+    };
+    check_size(&edition, "edition")?;
+    encryption::create_key(id, key);
+    content::store_body(id, payload.body)?;
+    store(&edition);
+    Ok(edition)
+}
+
+// end the primary sale of the caller's edition, no more copies can be minted
+#[ic_cdk::update]
+fn close_edition(edition_id: u64) -> Result<EditionSet, Error> {
+    let user = caller_user()?;
+    let edition = get(edition_id)?;
+    if edition.creator_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the creator can close this edition".to_string(),
+        });
+    }
+    let edition = EditionSet {
+        is_open: false,
+        ..edition
+    };
+    store(&edition);
+    Ok(edition)
+}
+
+// check that a user may buy the next copy of an edition
+fn check_edition_purchase(user_id: u64, edition_id: u64) -> Result<EditionSet, Error> {
+    let edition = get(edition_id)?;
+    if !edition.is_open || edition.minted >= edition.max_supply {
+        return Err(Error::InvalidPayload {
+            msg: "This edition is sold out".to_string(),
+        });
+    }
+    if edition.creator_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not buy your own recipe".to_string(),
+        });
+    }
+    Ok(edition)
+}
+
+// buy the next copy of an edition from its creator. The copy is minted to the
+// creator and sold to the caller through the regular purchase settlement, any
+// shortfall in the caller's balance is pulled from their ledger account first.
+// Like 'buy_recipe_nft', the copy is returned with its key wrapped for the buyer
+#[ic_cdk::update]
+async fn buy_edition(edition_id: u64) -> Result<RecipeContent, Error> {
+    let user = caller_user()?;
+    if user.encryption_key.is_none() {
+        return Err(Error::InvalidPayload {
+            msg: "Set an encryption key before buying recipes".to_string(),
+        });
+    }
+    let edition = check_edition_purchase(user.id, edition_id)?;
+    settlement::check_room(&user)?;
+    let ephemeral = encryption::random_bytes().await?;
+
+    if user.balance < edition.price {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = edition.price - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the purchase, the edition or balances may have changed during the ledger call
+    let user = caller_user()?;
+    let edition = check_edition_purchase(user.id, edition_id)?;
+    if user.balance < edition.price {
+        return Err(Error::InvalidPayload {
+            msg: "You do not have enough balance to buy this recipe".to_string(),
+        });
+    }
+
+    // mint the copy to the creator. Should the sale still fail, the copy
+    // stays with the creator, who can sell it like any other recipe
+    let number = edition.minted + 1;
+    let copy = Recipe {
+        id: next_id(),
+        title: edition.title.clone(),
+        category: edition.category.clone(),
+        description: edition.description.clone(),
+        price: edition.price,
+        user_id: edition.creator_id,
+        is_community: false,
+        is_for_sale: false,
+        reviews: vec![],
+        creator_id: Some(edition.creator_id),
+        royalty_bps: Some(edition.royalty_bps),
+        edition: Some(Edition {
+            edition_id,
+            number,
+            max_supply: edition.max_supply,
+        }),
+        license: Some(LicenseTier::Commercial),
+        rental_price_per_day: None,
+        teaser: edition.teaser.clone(),
+        image_url: edition.image_url.clone(),
+        servings: edition.servings,
+        prep_minutes: edition.prep_minutes,
+        cook_minutes: edition.cook_minutes,
+        nutrition: edition.nutrition.clone(),
+        media: None,
+    };
+    add_recipe_to_owner(edition.creator_id, copy.id)?;
+    RECIPE_STORAGE.with(|s| s.borrow_mut().insert(copy.id, copy.clone()));
+    pages::certify_recipe(copy.id);
+    store(&EditionSet {
+        minted: number,
+        ..edition.clone()
+    });
+    history::record(
+        TransactionKind::Mint,
+        Some(copy.id),
+        None,
+        Some(edition.creator_id),
+        None,
+    );
+
+    Settlement::purchase(copy.clone(), user.id, edition.price).apply()?;
+    let copy = RECIPE_STORAGE
+        .with(|recipes| recipes.borrow().get(&copy.id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", copy.id),
+        })?;
+    let public_key = user.encryption_key.unwrap_or_default();
+    content::unlocked_content(copy, &public_key, ephemeral)
+}
+
+// get an edition, the description is only shown to its creator. Buyers read
+// it from their copy
+#[ic_cdk::query]
+fn get_edition(edition_id: u64) -> Result<EditionSet, Error> {
+    let edition = get(edition_id)?;
+    let caller_id = user_by_principal(ic_cdk::caller()).map(|user| user.id);
+    if caller_id == Some(edition.creator_id) {
+        return Ok(edition);
+    }
+    Ok(EditionSet {
+        description: String::new(),
+        ..edition
+    })
+}
+
+// get every copy minted from an edition, in edition order
+#[ic_cdk::query]
+fn get_edition_copies(edition_id: u64) -> Result<Vec<RecipePreview>, Error> {
+    get(edition_id)?;
+    let mut copies: Vec<Recipe> = RECIPE_STORAGE.with(|recipes| {
+        recipes
+            .borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| {
+                r.edition
+                    .as_ref()
+                    .is_some_and(|edition| edition.edition_id == edition_id)
+            })
+            .collect()
+    });
+    copies.sort_by_key(|r| r.edition.as_ref().map(|edition| edition.number));
+    Ok(copies.into_iter().map(RecipePreview::from).collect())
+}
@@ -0,0 +1,203 @@
+use crate::{Error, RECIPE_KEY_STORAGE};
+use candid::{Decode, Encode};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// info string binding a wrapping key to its use
+const WRAP_INFO: &[u8] = b"recipe-nft-marketplace key wrap";
+
+// The symmetric key a recipe body is encrypted with. Nonces count up from
+// zero, so re-encrypting an edited body never repeats one under the same key.
+// Keys are stored in plaintext next to the bodies: they keep bodies out of
+// query responses, not away from anyone who can read the canister's memory
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecipeKey {
+    key: Vec<u8>,
+    next_nonce: u64,
+}
+
+impl Storable for RecipeKey {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RecipeKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A recipe body encrypted with ChaCha20-Poly1305 under its recipe key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SealedBody {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Storable for SealedBody {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SealedBody {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A recipe key encrypted for one user's X25519 public key. Clients derive the
+// wrapping key with HKDF-SHA256 from their shared secret with
+// 'ephemeral_public_key', salted with the ephemeral and their own public key,
+// and decrypt 'ciphertext' with ChaCha20-Poly1305 and an all-zero nonce
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WrappedKey {
+    ephemeral_public_key: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+// Get 32 fresh random bytes from the management canister
+pub(crate) async fn random_bytes() -> Result<[u8; 32], Error> {
+    match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((bytes,)) => bytes.try_into().map_err(|_| Error::CallFailed {
+            msg: "Could not get randomness for encryption".to_string(),
+        }),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!(
+                "Could not get randomness for encryption: {:?} {}",
+                code, msg
+            ),
+        }),
+    }
+}
+
+// Derive a per-record key from a random seed, used when migrating many
+// records with a single call to raw_rand
+pub(crate) fn derive_key(seed: &[u8], id: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(seed)
+        .chain_update(id.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+// check that a user's public encryption key is an X25519 key
+pub(crate) fn check_public_key(public_key: &[u8]) -> Result<(), Error> {
+    if public_key.len() != 32 {
+        return Err(Error::InvalidPayload {
+            msg: "Encryption keys must be 32 byte X25519 public keys".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn has_key(id: u64) -> bool {
+    RECIPE_KEY_STORAGE.with(|s| s.borrow().contains_key(&id))
+}
+
+// store a new recipe key, existing keys are never replaced
+pub(crate) fn create_key(id: u64, key: [u8; 32]) {
+    if has_key(id) {
+        return;
+    }
+    let key = RecipeKey {
+        key: key.to_vec(),
+        next_nonce: 0,
+    };
+    RECIPE_KEY_STORAGE.with(|s| s.borrow_mut().insert(id, key));
+}
+
+fn get_key(id: u64) -> Result<RecipeKey, Error> {
+    RECIPE_KEY_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("no encryption key for recipe id: {}", id),
+        })
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+// encrypt bytes under a recipe key with its next nonce
+pub(crate) fn seal(id: u64, plaintext: &[u8]) -> Result<SealedBody, Error> {
+    let key = get_key(id)?;
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&key.next_nonce.to_be_bytes());
+    let ciphertext = cipher(&key.key)
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::InvalidPayload {
+            msg: "Could not encrypt recipe".to_string(),
+        })?;
+    let next = RecipeKey {
+        next_nonce: key.next_nonce + 1,
+        ..key
+    };
+    RECIPE_KEY_STORAGE.with(|s| s.borrow_mut().insert(id, next));
+    Ok(SealedBody {
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+// decrypt a body sealed under a recipe key
+pub(crate) fn open(id: u64, sealed: &SealedBody) -> Result<Vec<u8>, Error> {
+    let key = get_key(id)?;
+    if sealed.nonce.len() != 12 {
+        return Err(Error::InvalidPayload {
+            msg: format!("Malformed ciphertext for recipe id: {}", id),
+        });
+    }
+    cipher(&key.key)
+        .decrypt(Nonce::from_slice(&sealed.nonce), sealed.ciphertext.as_ref())
+        .map_err(|_| Error::InvalidPayload {
+            msg: format!("Could not decrypt recipe id: {}", id),
+        })
+}
+
+// encrypt a recipe key for a user's public key, 'ephemeral' must be fresh randomness
+pub(crate) fn wrap(id: u64, public_key: &[u8], ephemeral: [u8; 32]) -> Result<WrappedKey, Error> {
+    check_public_key(public_key)?;
+    let key = get_key(id)?;
+    let mut recipient = [0u8; 32];
+    recipient.copy_from_slice(public_key);
+
+    let secret = StaticSecret::from(ephemeral);
+    let ephemeral_public_key = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(recipient));
+    if !shared.was_contributory() {
+        return Err(Error::InvalidPayload {
+            msg: "Invalid encryption key".to_string(),
+        });
+    }
+
+    let salt = [ephemeral_public_key.as_bytes().as_slice(), &recipient].concat();
+    let mut wrapping_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(WRAP_INFO, &mut wrapping_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    // every wrapping key is used once, so a fixed nonce is safe
+    let ciphertext = cipher(&wrapping_key)
+        .encrypt(Nonce::from_slice(&[0u8; 12]), key.key.as_ref())
+        .map_err(|_| Error::InvalidPayload {
+            msg: "Could not wrap recipe key".to_string(),
+        })?;
+    Ok(WrappedKey {
+        ephemeral_public_key: ephemeral_public_key.as_bytes().to_vec(),
+        ciphertext,
+    })
+}
@@ -0,0 +1,348 @@
+use crate::icrc7::Value;
+use crate::{Error, HISTORY_STORAGE};
+use candid::{Decode, Encode, Nat};
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+// where the marketplace's block types are described
+const BLOCK_TYPES_URL: &str =
+    "https://github.com/Mhezron/recipe-nft-marketplace#transaction-history";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum TransactionKind {
+    Mint,
+    List,
+    Sale,
+    Transfer,
+    Fund,
+    Withdraw,
+    Royalty,
+    Fee,
+    TreasuryWithdraw,
+    Bid,
+    BidRefund,
+    Rental,
+    Subscription,
+}
+
+impl TransactionKind {
+    // ICRC-3 block type of each kind of transaction. Transactions record user
+    // ids rather than accounts, so none of them follow the ICRC-7 schemas
+    fn btype(&self) -> &'static str {
+        match self {
+            TransactionKind::Mint => "recipe_mint",
+            TransactionKind::List => "recipe_list",
+            TransactionKind::Sale => "recipe_sale",
+            TransactionKind::Transfer => "recipe_transfer",
+            TransactionKind::Fund => "recipe_fund",
+            TransactionKind::Withdraw => "recipe_withdraw",
+            TransactionKind::Royalty => "recipe_royalty",
+            TransactionKind::Fee => "recipe_fee",
+            TransactionKind::TreasuryWithdraw => "recipe_treasury_withdraw",
+            TransactionKind::Bid => "recipe_bid",
+            TransactionKind::BidRefund => "recipe_bid_refund",
+            TransactionKind::Rental => "recipe_rental",
+            TransactionKind::Subscription => "recipe_subscription",
+        }
+    }
+}
+
+// A block of the append-only transaction log. 'phash' is the hash of the
+// previous block, chaining every block to the ones before it.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Transaction {
+    index: u64,
+    timestamp: u64,
+    kind: TransactionKind,
+    recipe_id: Option<u64>,
+    from_user_id: Option<u64>,
+    to_user_id: Option<u64>,
+    amount: Option<u64>,
+    phash: Option<Vec<u8>>,
+    btype: String,
+}
+
+impl Storable for Transaction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Transaction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Transaction {
+    // ICRC-3 generic value representation of the block
+    fn to_value(&self) -> Value {
+        let mut tx = vec![];
+        if let Some(recipe_id) = self.recipe_id {
+            tx.push(("tid".to_string(), Value::Nat(Nat::from(recipe_id))));
+        }
+        if let Some(from) = self.from_user_id {
+            tx.push(("from".to_string(), Value::Nat(Nat::from(from))));
+        }
+        if let Some(to) = self.to_user_id {
+            tx.push(("to".to_string(), Value::Nat(Nat::from(to))));
+        }
+        if let Some(amount) = self.amount {
+            tx.push(("amt".to_string(), Value::Nat(Nat::from(amount))));
+        }
+
+        let mut block = vec![
+            ("btype".to_string(), Value::Text(self.btype.clone())),
+            ("ts".to_string(), Value::Nat(Nat::from(self.timestamp))),
+            ("tx".to_string(), Value::Map(tx)),
+        ];
+        if let Some(phash) = &self.phash {
+            block.push(("phash".to_string(), Value::Blob(phash.clone())));
+        }
+        Value::Map(block)
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        hash_value(&self.to_value()).to_vec()
+    }
+}
+
+// ICRC-3 representation-independent hash of a value
+fn hash_value(value: &Value) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    match value {
+        Value::Nat(n) => {
+            let mut buf = vec![];
+            n.encode(&mut buf).expect("can always leb128 encode a nat");
+            hasher.update(buf);
+        }
+        Value::Int(i) => {
+            let mut buf = vec![];
+            i.encode(&mut buf)
+                .expect("can always sleb128 encode an int");
+            hasher.update(buf);
+        }
+        Value::Text(text) => hasher.update(text.as_bytes()),
+        Value::Blob(blob) => hasher.update(blob),
+        Value::Array(values) => {
+            for value in values {
+                hasher.update(hash_value(value));
+            }
+        }
+        Value::Map(entries) => {
+            let mut hashes: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut entry = Sha256::digest(key.as_bytes()).to_vec();
+                    entry.extend_from_slice(&hash_value(value));
+                    entry
+                })
+                .collect();
+            hashes.sort();
+            for entry in hashes {
+                hasher.update(entry);
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+// Append a transaction to the log, returning its block index
+pub(crate) fn record(
+    kind: TransactionKind,
+    recipe_id: Option<u64>,
+    from_user_id: Option<u64>,
+    to_user_id: Option<u64>,
+    amount: Option<u64>,
+) -> u64 {
+    HISTORY_STORAGE.with(|s| {
+        let mut log = s.borrow_mut();
+        let index = log.len();
+        let phash = match index {
+            0 => None,
+            _ => log.get(&(index - 1)).map(|parent| parent.hash()),
+        };
+        let transaction = Transaction {
+            index,
+            timestamp: ic_cdk::api::time(),
+            kind,
+            recipe_id,
+            from_user_id,
+            to_user_id,
+            amount,
+            phash,
+            btype: kind.btype().to_string(),
+        };
+        log.insert(index, transaction);
+        index
+    })
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+candid::define_function!(pub(crate) GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BlockType {
+    block_type: String,
+    url: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveInfo {
+    canister_id: candid::Principal,
+    start: Nat,
+    end: Nat,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GetArchivesArgs {
+    from: Option<candid::Principal>,
+}
+
+// Paging arguments for the per-user and per-recipe history queries
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct HistoryPayload {
+    id: u64,
+    start: Option<u64>,
+    length: Option<u64>,
+}
+
+fn to_u64(n: &Nat) -> u64 {
+    u64::try_from(&n.0).unwrap_or(u64::MAX)
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = HISTORY_STORAGE.with(|s| s.borrow().len());
+    let mut blocks = vec![];
+    for arg in args {
+        let start = to_u64(&arg.start);
+        let length = to_u64(&arg.length).min(MAX_BLOCKS_PER_REQUEST);
+        let end = start.saturating_add(length).min(log_length);
+        HISTORY_STORAGE.with(|s| {
+            let log = s.borrow();
+            for (index, transaction) in log.range(start..end) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(index),
+                    block: transaction.to_value(),
+                });
+            }
+        });
+        if blocks.len() as u64 >= MAX_BLOCKS_PER_REQUEST {
+            blocks.truncate(MAX_BLOCKS_PER_REQUEST as usize);
+            break;
+        }
+    }
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        // the log is never archived
+        archived_blocks: vec![],
+    }
+}
+
+#[ic_cdk::query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    vec![]
+}
+
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<BlockType> {
+    [
+        TransactionKind::Mint,
+        TransactionKind::List,
+        TransactionKind::Sale,
+        TransactionKind::Transfer,
+        TransactionKind::Fund,
+        TransactionKind::Withdraw,
+        TransactionKind::Royalty,
+        TransactionKind::Fee,
+        TransactionKind::TreasuryWithdraw,
+        TransactionKind::Bid,
+        TransactionKind::BidRefund,
+        TransactionKind::Rental,
+        TransactionKind::Subscription,
+    ]
+    .iter()
+    .map(|kind| BlockType {
+        block_type: kind.btype().to_string(),
+        url: BLOCK_TYPES_URL.to_string(),
+    })
+    .collect()
+}
+
+// page through the transactions matching a filter, oldest first
+fn history(payload: HistoryPayload, filter: impl Fn(&Transaction) -> bool) -> Vec<Transaction> {
+    let start = payload.start.unwrap_or(0) as usize;
+    let length = payload
+        .length
+        .unwrap_or(MAX_BLOCKS_PER_REQUEST)
+        .min(MAX_BLOCKS_PER_REQUEST) as usize;
+    HISTORY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .filter(|transaction| filter(transaction))
+            .skip(start)
+            .take(length)
+            .collect()
+    })
+}
+
+// get the transactions a user took part in
+#[ic_cdk::query]
+fn get_user_history(payload: HistoryPayload) -> Result<Vec<Transaction>, Error> {
+    let id = payload.id;
+    let transactions = history(payload, |transaction| {
+        transaction.from_user_id == Some(id) || transaction.to_user_id == Some(id)
+    });
+    match transactions.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no transactions found for user id: {}", id),
+        }),
+        _ => Ok(transactions),
+    }
+}
+
+// get the transactions involving a recipe
+#[ic_cdk::query]
+fn get_recipe_history(payload: HistoryPayload) -> Result<Vec<Transaction>, Error> {
+    let id = payload.id;
+    let transactions = history(payload, |transaction| transaction.recipe_id == Some(id));
+    match transactions.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no transactions found for recipe id: {}", id),
+        }),
+        _ => Ok(transactions),
+    }
+}
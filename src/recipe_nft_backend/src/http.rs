@@ -0,0 +1,196 @@
+use crate::{media, pages, site_url, CERTIFIED_ASSETS};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ic_certification::{labeled, labeled_hash, AsHashTree, Hash};
+use sha2::{Digest, Sha256};
+
+// label the asset hashes are certified under, as the http gateway expects
+const ASSETS_LABEL: &[u8] = b"http_assets";
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+    // never set, pages are certified by the updates changing them
+    upgrade: Option<bool>,
+}
+
+// Where the next chunk of a streamed media starts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StreamingToken {
+    pub(crate) media_id: u64,
+    pub(crate) index: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StreamingCallbackHttpResponse {
+    pub(crate) body: Vec<u8>,
+    pub(crate) token: Option<StreamingToken>,
+}
+
+candid::define_function!(pub(crate) StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+impl HttpResponse {
+    // a response whose body continues in chunks fetched with 'token'
+    pub(crate) fn streamed(
+        status_code: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        token: Option<StreamingToken>,
+    ) -> Self {
+        HttpResponse {
+            status_code,
+            headers,
+            body,
+            streaming_strategy: token.map(|token| StreamingStrategy::Callback {
+                callback: StreamingCallback::new(
+                    ic_cdk::id(),
+                    "http_request_streaming_callback".to_string(),
+                ),
+                token,
+            }),
+            upgrade: None,
+        }
+    }
+}
+
+fn text(status_code: u16, body: &str) -> HttpResponse {
+    HttpResponse::streamed(
+        status_code,
+        vec![(
+            "Content-Type".to_string(),
+            "text/plain; charset=utf-8".to_string(),
+        )],
+        body.as_bytes().to_vec(),
+        None,
+    )
+}
+
+pub(crate) fn not_found() -> HttpResponse {
+    text(404, "Not found")
+}
+
+// the address the canister is served at by the http gateway, as configured by
+// the contract owner for local replicas and custom domains
+pub(crate) fn base_url() -> String {
+    match site_url() {
+        Some(site_url) => site_url.trim_end_matches('/').to_string(),
+        None => format!("https://{}.icp0.io", ic_cdk::id()),
+    }
+}
+
+// the request's path, without its query string
+fn path_of(request: &HttpRequest) -> &str {
+    request.url.split(['?', '#']).next().unwrap_or_default()
+}
+
+fn set_root_hash() {
+    let root = CERTIFIED_ASSETS.with(|tree| tree.borrow().root_hash());
+    ic_cdk::api::set_certified_data(&labeled_hash(ASSETS_LABEL, &root));
+}
+
+// certify the body served at a path by its sha256, only in update calls
+pub(crate) fn certify(path: &str, sha256: Hash) {
+    CERTIFIED_ASSETS.with(|tree| tree.borrow_mut().insert(path.to_string(), sha256));
+    set_root_hash();
+}
+
+pub(crate) fn uncertify(path: &str) {
+    CERTIFIED_ASSETS.with(|tree| tree.borrow_mut().delete(path.as_bytes()));
+    set_root_hash();
+}
+
+fn is_certified(path: &str, sha256: &Hash) -> bool {
+    CERTIFIED_ASSETS.with(|tree| tree.borrow().get(path.as_bytes()) == Some(sha256))
+}
+
+// the header proving a certified body to the gateway, None outside queries
+pub(crate) fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let witness = CERTIFIED_ASSETS.with(|tree| tree.borrow().witness(path.as_bytes()));
+    let tree = labeled(ASSETS_LABEL, witness);
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().ok()?;
+    serde::Serialize::serialize(&tree, &mut serializer).ok()?;
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(serializer.into_inner())
+        ),
+    ))
+}
+
+fn page_response(path: &str, page: pages::Page) -> HttpResponse {
+    let mut headers = vec![
+        ("Content-Type".to_string(), page.content_type.to_string()),
+        (
+            "Cache-Control".to_string(),
+            "public, max-age=60".to_string(),
+        ),
+    ];
+    headers.extend(certificate_header(path));
+    HttpResponse::streamed(200, headers, page.body, None)
+}
+
+// serve the canister's http gateway requests. Pages are rendered from the
+// current records, and served once the updates changing them have certified
+// them
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return text(405, "Method not allowed");
+    }
+    let path = path_of(&request);
+    if let Some(id) = path.strip_prefix("/media/") {
+        return match id.parse() {
+            Ok(id) => media::serve(id),
+            Err(_) => not_found(),
+        };
+    }
+    match pages::render(path) {
+        Some(page) if is_certified(path, &Sha256::digest(&page.body).into()) => {
+            page_response(path, page)
+        }
+        _ => not_found(),
+    }
+}
+
+// serve a page in an update call, for gateways that ask for one. Pages are
+// not certified here, anyone could call it
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return text(405, "Method not allowed");
+    }
+    let path = path_of(&request);
+    match pages::render(path) {
+        Some(page) => page_response(path, page),
+        None => not_found(),
+    }
+}
+
+// get the next chunk of a media streamed by 'http_request'
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    media::next_chunk(token)
+}
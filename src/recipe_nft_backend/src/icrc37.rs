@@ -0,0 +1,754 @@
+use crate::icrc7::{
+    account_user, failure_message, is_default_subaccount, token_to_recipe_id, transfer_ownership,
+    Account, Value, MAX_MEMO_SIZE, MAX_QUERY_BATCH_SIZE, MAX_UPDATE_BATCH_SIZE,
+};
+use crate::{authenticated_caller, APPROVAL_STORAGE, ID_COUNTER, RECIPE_STORAGE};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+const MAX_APPROVALS_PER_TOKEN_OR_COLLECTION: u64 = 10;
+const MAX_REVOKE_APPROVALS: u64 = 20;
+// created_at_time must fall within this window before the current time
+pub(crate) const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub(crate) const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+const SUBACCOUNT_SIZE: usize = 32;
+
+// An approval for a spender to transfer one recipe, or every recipe of its
+// owner when 'token_id' is None
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Approval {
+    id: u64,
+    token_id: Option<u64>,
+    owner_id: u64,
+    info: ApprovalInfo,
+}
+
+impl Approval {
+    fn is_expired(&self, now: u64) -> bool {
+        self.info
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl Storable for Approval {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Approval {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApprovalInfo {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApproveTokenArg {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type ApproveTokenResult = Result<Nat, ApproveTokenError>;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApproveCollectionArg {
+    approval_info: ApprovalInfo,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type ApproveCollectionResult = Result<Nat, ApproveCollectionError>;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RevokeTokenApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type RevokeTokenApprovalResult = Result<Nat, RevokeTokenApprovalError>;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RevokeCollectionApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type RevokeCollectionApprovalResult = Result<Nat, RevokeCollectionApprovalError>;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IsApprovedArg {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TokenApproval {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+pub(crate) type CollectionApproval = ApprovalInfo;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TransferFromArg {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type TransferFromResult = Result<Nat, TransferFromError>;
+
+// Outcome of checking a request's created_at_time against the ledger time
+pub(crate) enum TimeCheck {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+pub(crate) fn check_created_at(created_at_time: Option<u64>) -> Result<(), TimeCheck> {
+    let now = ic_cdk::api::time();
+    match created_at_time {
+        Some(created_at) if created_at.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now => {
+            Err(TimeCheck::TooOld)
+        }
+        Some(created_at) if created_at > now.saturating_add(PERMITTED_DRIFT) => {
+            Err(TimeCheck::CreatedInFuture { ledger_time: now })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn batch_error(max: u64) -> (Nat, String) {
+    (
+        Nat::from(1u64),
+        format!("At most {} entries per batch", max),
+    )
+}
+
+// Memos and subaccounts are bounded, which also keeps an approval within its
+// stored size
+fn check_lengths(
+    memo: &Option<Vec<u8>>,
+    subaccounts: &[&Option<Vec<u8>>],
+) -> Result<(), (Nat, String)> {
+    if memo.as_ref().map_or(0, |memo| memo.len()) as u64 > MAX_MEMO_SIZE {
+        return Err((
+            Nat::from(5u64),
+            format!("Memo is longer than {} bytes", MAX_MEMO_SIZE),
+        ));
+    }
+    if subaccounts.iter().any(|subaccount| {
+        subaccount
+            .as_ref()
+            .is_some_and(|s| s.len() != SUBACCOUNT_SIZE)
+    }) {
+        return Err((
+            Nat::from(5u64),
+            format!("Subaccounts must be {} bytes", SUBACCOUNT_SIZE),
+        ));
+    }
+    Ok(())
+}
+
+// All unexpired approvals matching a filter
+fn active_approvals(filter: impl Fn(&Approval) -> bool) -> Vec<Approval> {
+    let now = ic_cdk::api::time();
+    APPROVAL_STORAGE.with(|approvals| {
+        approvals
+            .borrow()
+            .iter()
+            .map(|(_, approval)| approval)
+            .filter(|approval| !approval.is_expired(now) && filter(approval))
+            .collect()
+    })
+}
+
+fn remove_approvals(approvals: &[Approval]) {
+    APPROVAL_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        for approval in approvals {
+            storage.remove(&approval.id);
+        }
+    });
+}
+
+// Store an approval, replacing any existing one for the same spender and scope
+fn store_approval(token_id: Option<u64>, owner_id: u64, info: ApprovalInfo) -> Result<u64, String> {
+    let now = ic_cdk::api::time();
+    let same_scope: Vec<Approval> = APPROVAL_STORAGE.with(|approvals| {
+        approvals
+            .borrow()
+            .iter()
+            .map(|(_, approval)| approval)
+            .filter(|approval| approval.token_id == token_id && approval.owner_id == owner_id)
+            .collect()
+    });
+    // drop expired approvals and the one being replaced
+    let stale: Vec<Approval> = same_scope
+        .iter()
+        .filter(|approval| approval.is_expired(now) || approval.info.spender == info.spender)
+        .cloned()
+        .collect();
+    remove_approvals(&stale);
+    if (same_scope.len() - stale.len()) as u64 >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+        return Err(format!(
+            "At most {} approvals are allowed per token or collection",
+            MAX_APPROVALS_PER_TOKEN_OR_COLLECTION
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let approval = Approval {
+        id,
+        token_id,
+        owner_id,
+        info,
+    };
+    APPROVAL_STORAGE.with(|s| s.borrow_mut().insert(id, approval));
+    Ok(id)
+}
+
+// Token approvals do not survive a change of owner
+pub(crate) fn clear_token_approvals(recipe_id: u64) {
+    let approvals = APPROVAL_STORAGE.with(|approvals| {
+        approvals
+            .borrow()
+            .iter()
+            .map(|(_, approval)| approval)
+            .filter(|approval| approval.token_id == Some(recipe_id))
+            .collect::<Vec<Approval>>()
+    });
+    remove_approvals(&approvals);
+}
+
+// Check whether a spender may move a recipe on behalf of its owner
+fn is_approved(spender: &Account, owner_id: u64, recipe_id: u64) -> bool {
+    !active_approvals(|approval| {
+        approval.owner_id == owner_id
+            && approval.info.spender == *spender
+            && approval
+                .token_id
+                .is_none_or(|token_id| token_id == recipe_id)
+    })
+    .is_empty()
+}
+
+#[ic_cdk::query]
+fn icrc37_metadata() -> Vec<(String, Value)> {
+    vec![
+        (
+            "icrc37:max_approvals_per_token_or_collection".to_string(),
+            Value::Nat(Nat::from(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION)),
+        ),
+        (
+            "icrc37:max_revoke_approvals".to_string(),
+            Value::Nat(Nat::from(MAX_REVOKE_APPROVALS)),
+        ),
+        (
+            "icrc37:max_memo_size".to_string(),
+            Value::Nat(Nat::from(MAX_MEMO_SIZE)),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION))
+}
+
+#[ic_cdk::query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(MAX_REVOKE_APPROVALS))
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => return vec![Some(Err(ApproveTokenError::Unauthorized))],
+    };
+    if args.len() as u64 > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveTokenError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    args.into_iter()
+        .map(|arg| Some(approve_token(caller, arg)))
+        .collect()
+}
+
+fn approve_token(caller: Principal, arg: ApproveTokenArg) -> ApproveTokenResult {
+    match check_created_at(Some(arg.approval_info.created_at_time)) {
+        Err(TimeCheck::TooOld) => return Err(ApproveTokenError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(ApproveTokenError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    if let Err((error_code, message)) = check_lengths(
+        &arg.approval_info.memo,
+        &[
+            &arg.approval_info.from_subaccount,
+            &arg.approval_info.spender.subaccount,
+        ],
+    ) {
+        return Err(ApproveTokenError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    let recipe_id =
+        token_to_recipe_id(&arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    let recipe = RECIPE_STORAGE
+        .with(|recipes| recipes.borrow().get(&recipe_id))
+        .ok_or(ApproveTokenError::NonExistingTokenId)?;
+
+    let owner = account_user(&Account {
+        owner: caller,
+        subaccount: arg.approval_info.from_subaccount.clone(),
+    })
+    .ok_or(ApproveTokenError::Unauthorized)?;
+    if owner.id != recipe.user_id {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if arg.approval_info.spender.owner == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+
+    match store_approval(Some(recipe_id), owner.id, arg.approval_info) {
+        Ok(id) => Ok(Nat::from(id)),
+        Err(message) => Err(ApproveTokenError::GenericError {
+            error_code: Nat::from(2u64),
+            message,
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<ApproveCollectionResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return vec![Some(Err(ApproveCollectionError::GenericError {
+                error_code: Nat::from(3u64),
+                message: "Anonymous principals are not allowed, please sign in".to_string(),
+            }))]
+        }
+    };
+    if args.len() as u64 > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    args.into_iter()
+        .map(|arg| Some(approve_collection(caller, arg)))
+        .collect()
+}
+
+fn approve_collection(caller: Principal, arg: ApproveCollectionArg) -> ApproveCollectionResult {
+    match check_created_at(Some(arg.approval_info.created_at_time)) {
+        Err(TimeCheck::TooOld) => return Err(ApproveCollectionError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(ApproveCollectionError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    if let Err((error_code, message)) = check_lengths(
+        &arg.approval_info.memo,
+        &[
+            &arg.approval_info.from_subaccount,
+            &arg.approval_info.spender.subaccount,
+        ],
+    ) {
+        return Err(ApproveCollectionError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    let owner = match account_user(&Account {
+        owner: caller,
+        subaccount: arg.approval_info.from_subaccount.clone(),
+    }) {
+        Some(owner) => owner,
+        None => {
+            return Err(ApproveCollectionError::GenericError {
+                error_code: Nat::from(3u64),
+                message: format!("No user is registered for principal: {}", caller),
+            })
+        }
+    };
+    if arg.approval_info.spender.owner == caller {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+
+    match store_approval(None, owner.id, arg.approval_info) {
+        Ok(id) => Ok(Nat::from(id)),
+        Err(message) => Err(ApproveCollectionError::GenericError {
+            error_code: Nat::from(2u64),
+            message,
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => return vec![Some(Err(RevokeTokenApprovalError::Unauthorized))],
+    };
+    if args.len() as u64 > MAX_REVOKE_APPROVALS {
+        let (error_code, message) = batch_error(MAX_REVOKE_APPROVALS);
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    args.into_iter()
+        .map(|arg| Some(revoke_token_approval(caller, arg)))
+        .collect()
+}
+
+fn revoke_token_approval(
+    caller: Principal,
+    arg: RevokeTokenApprovalArg,
+) -> RevokeTokenApprovalResult {
+    match check_created_at(arg.created_at_time) {
+        Err(TimeCheck::TooOld) => return Err(RevokeTokenApprovalError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(RevokeTokenApprovalError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    if let Err((error_code, message)) = check_lengths(&arg.memo, &[&arg.from_subaccount]) {
+        return Err(RevokeTokenApprovalError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    let recipe_id =
+        token_to_recipe_id(&arg.token_id).ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    let recipe = RECIPE_STORAGE
+        .with(|recipes| recipes.borrow().get(&recipe_id))
+        .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    let owner = account_user(&Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    })
+    .ok_or(RevokeTokenApprovalError::Unauthorized)?;
+    if owner.id != recipe.user_id {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    let revoked = active_approvals(|approval| {
+        approval.token_id == Some(recipe_id)
+            && approval.owner_id == owner.id
+            && arg
+                .spender
+                .as_ref()
+                .is_none_or(|spender| approval.info.spender == *spender)
+    });
+    if revoked.is_empty() {
+        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+    }
+    remove_approvals(&revoked);
+    Ok(Nat::from(revoked[0].id))
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => {
+            return vec![Some(Err(RevokeCollectionApprovalError::GenericError {
+                error_code: Nat::from(3u64),
+                message: "Anonymous principals are not allowed, please sign in".to_string(),
+            }))]
+        }
+    };
+    if args.len() as u64 > MAX_REVOKE_APPROVALS {
+        let (error_code, message) = batch_error(MAX_REVOKE_APPROVALS);
+        return vec![Some(Err(
+            RevokeCollectionApprovalError::GenericBatchError {
+                error_code,
+                message,
+            },
+        ))];
+    }
+    args.into_iter()
+        .map(|arg| Some(revoke_collection_approval(caller, arg)))
+        .collect()
+}
+
+fn revoke_collection_approval(
+    caller: Principal,
+    arg: RevokeCollectionApprovalArg,
+) -> RevokeCollectionApprovalResult {
+    match check_created_at(arg.created_at_time) {
+        Err(TimeCheck::TooOld) => return Err(RevokeCollectionApprovalError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(RevokeCollectionApprovalError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    if let Err((error_code, message)) = check_lengths(&arg.memo, &[&arg.from_subaccount]) {
+        return Err(RevokeCollectionApprovalError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    let owner = account_user(&Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    })
+    .ok_or(RevokeCollectionApprovalError::ApprovalDoesNotExist)?;
+
+    let revoked = active_approvals(|approval| {
+        approval.token_id.is_none()
+            && approval.owner_id == owner.id
+            && arg
+                .spender
+                .as_ref()
+                .is_none_or(|spender| approval.info.spender == *spender)
+    });
+    if revoked.is_empty() {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    remove_approvals(&revoked);
+    Ok(Nat::from(revoked[0].id))
+}
+
+#[ic_cdk::query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    args.iter()
+        .take(MAX_QUERY_BATCH_SIZE as usize)
+        .map(|arg| {
+            let recipe = token_to_recipe_id(&arg.token_id)
+                .and_then(|id| RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&id)));
+            // recipes are only held on default subaccounts, so approvals from others never apply
+            match recipe {
+                Some(recipe) => {
+                    is_default_subaccount(&arg.from_subaccount)
+                        && is_approved(&arg.spender, recipe.user_id, recipe.id)
+                }
+                None => false,
+            }
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc37_get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    let recipe_id = match token_to_recipe_id(&token_id) {
+        Some(id) => id,
+        None => return vec![],
+    };
+    let mut approvals = active_approvals(|approval| approval.token_id == Some(recipe_id));
+    approvals.sort_by_key(|approval| approval.id);
+    page(approvals, prev.map(|p| p.approval_info.spender), take)
+        .into_iter()
+        .map(|approval| TokenApproval {
+            token_id: token_id.clone(),
+            approval_info: approval.info,
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<CollectionApproval>,
+    take: Option<Nat>,
+) -> Vec<CollectionApproval> {
+    let owner = match account_user(&owner) {
+        Some(owner) => owner,
+        None => return vec![],
+    };
+    let mut approvals =
+        active_approvals(|approval| approval.token_id.is_none() && approval.owner_id == owner.id);
+    approvals.sort_by_key(|approval| approval.id);
+    page(approvals, prev.map(|p| p.spender), take)
+        .into_iter()
+        .map(|approval| approval.info)
+        .collect()
+}
+
+// skip past the approval of the previous page's last spender, then take a page
+fn page(approvals: Vec<Approval>, prev: Option<Account>, take: Option<Nat>) -> Vec<Approval> {
+    let take = take
+        .and_then(|t| u64::try_from(&t.0).ok())
+        .unwrap_or(MAX_QUERY_BATCH_SIZE)
+        .min(MAX_QUERY_BATCH_SIZE) as usize;
+    let start = match prev {
+        Some(prev) => approvals
+            .iter()
+            .position(|approval| approval.info.spender == prev)
+            .map_or(0, |i| i + 1),
+        None => 0,
+    };
+    approvals.into_iter().skip(start).take(take).collect()
+}
+
+#[ic_cdk::update]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => return vec![Some(Err(TransferFromError::Unauthorized))],
+    };
+    if args.len() as u64 > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(TransferFromError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    args.into_iter()
+        .map(|arg| Some(transfer_from(caller, arg)))
+        .collect()
+}
+
+fn transfer_from(caller: Principal, arg: TransferFromArg) -> TransferFromResult {
+    match check_created_at(arg.created_at_time) {
+        Err(TimeCheck::TooOld) => return Err(TransferFromError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(TransferFromError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    if let Err((error_code, message)) = check_lengths(&arg.memo, &[&arg.spender_subaccount]) {
+        return Err(TransferFromError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    let recipe_id =
+        token_to_recipe_id(&arg.token_id).ok_or(TransferFromError::NonExistingTokenId)?;
+    let recipe = RECIPE_STORAGE
+        .with(|recipes| recipes.borrow().get(&recipe_id))
+        .ok_or(TransferFromError::NonExistingTokenId)?;
+
+    let from = account_user(&arg.from).ok_or(TransferFromError::Unauthorized)?;
+    if from.id != recipe.user_id {
+        return Err(TransferFromError::Unauthorized);
+    }
+    let spender = Account {
+        owner: caller,
+        subaccount: arg.spender_subaccount,
+    };
+    if !is_approved(&spender, from.id, recipe.id) {
+        return Err(TransferFromError::Unauthorized);
+    }
+    if recipe.is_community {
+        return Err(TransferFromError::GenericError {
+            error_code: Nat::from(4u64),
+            message: "Community recipes can not be transferred".to_string(),
+        });
+    }
+
+    let to = account_user(&arg.to).ok_or(TransferFromError::InvalidRecipient)?;
+    if to.id == from.id {
+        return Err(TransferFromError::InvalidRecipient);
+    }
+
+    match transfer_ownership(recipe, &to) {
+        Ok(index) => Ok(Nat::from(index)),
+        Err(error) => match failure_message(error) {
+            Some(message) => Err(TransferFromError::GenericError {
+                error_code: Nat::from(6u64),
+                message,
+            }),
+            None => Err(TransferFromError::Unauthorized),
+        },
+    }
+}
@@ -0,0 +1,571 @@
+use crate::icrc37::{check_created_at, TimeCheck, PERMITTED_DRIFT, TX_WINDOW};
+use crate::license::{license_of, LicenseTier};
+use crate::media;
+use crate::settlement::{royalty_for, Settlement};
+use crate::{
+    authenticated_caller, user_by_principal, Error, Recipe, User, RECENT_TRANSFER_STORAGE,
+    RECIPE_STORAGE, USER_STORAGE,
+};
+use candid::{Encode, Nat, Principal};
+use ic_stable_structures::storable::Blob;
+use sha2::{Digest, Sha256};
+
+// Collection parameters reported through the ICRC-7 metadata queries
+const SYMBOL: &str = "RCP";
+pub(crate) const NAME: &str = "Recipe NFT Marketplace";
+const DESCRIPTION: &str = "Recipes traded as NFTs on the recipe marketplace";
+pub(crate) const MAX_QUERY_BATCH_SIZE: u64 = 100;
+pub(crate) const MAX_UPDATE_BATCH_SIZE: u64 = 20;
+const DEFAULT_TAKE_VALUE: u64 = 100;
+const MAX_TAKE_VALUE: u64 = 500;
+pub(crate) const MAX_MEMO_SIZE: u64 = 32;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Account {
+    pub(crate) owner: Principal,
+    pub(crate) subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    // Recipes are owned by users, which only have the default subaccount
+    pub(crate) fn is_default(&self) -> bool {
+        is_default_subaccount(&self.subaccount)
+    }
+}
+
+pub(crate) fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    match subaccount {
+        Some(subaccount) => subaccount.iter().all(|b| *b == 0),
+        None => true,
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+// ICRC-3 style generic value used for metadata
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub(crate) type TransferResult = Result<Nat, TransferError>;
+
+// token ids are recipe ids
+pub(crate) fn token_to_recipe_id(token_id: &Nat) -> Option<u64> {
+    u64::try_from(&token_id.0).ok()
+}
+
+fn recipe_by_token(token_id: &Nat) -> Option<Recipe> {
+    let id = token_to_recipe_id(token_id)?;
+    RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&id))
+}
+
+// Get the account of the user who owns a recipe
+pub(crate) fn recipe_owner_account(recipe: &Recipe) -> Option<Account> {
+    USER_STORAGE
+        .with(|users| users.borrow().get(&recipe.user_id))
+        .map(|user| user.principal.into())
+}
+
+// Get the user an account refers to, recipes are only held on default subaccounts
+pub(crate) fn account_user(account: &Account) -> Option<User> {
+    if !account.is_default() {
+        return None;
+    }
+    user_by_principal(account.owner)
+}
+
+// Ids of the recipes owned by a user, in ascending order
+fn recipe_ids_of(user: &User) -> Vec<u64> {
+    let mut ids = user.recipes.clone();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+// apply the prev/take pagination used by the ICRC-7 listing queries
+fn paginate(ids: impl Iterator<Item = u64>, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let prev = prev.and_then(|p| token_to_recipe_id(&p));
+    let take = take
+        .and_then(|t| u64::try_from(&t.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE) as usize;
+    ids.filter(|id| prev.is_none_or(|prev| *id > prev))
+        .take(take)
+        .map(Nat::from)
+        .collect()
+}
+
+pub(crate) fn recipe_metadata(recipe: &Recipe) -> Vec<(String, Value)> {
+    let mut metadata = vec![
+        ("icrc7:name".to_string(), Value::Text(recipe.title.clone())),
+        // token metadata is public, it only carries the recipe's teaser
+        (
+            "icrc7:description".to_string(),
+            Value::Text(recipe.teaser.clone().unwrap_or_default()),
+        ),
+        (
+            "recipe:category".to_string(),
+            Value::Text(recipe.category.clone()),
+        ),
+        (
+            "recipe:price".to_string(),
+            Value::Nat(Nat::from(recipe.price)),
+        ),
+        (
+            "recipe:is_for_sale".to_string(),
+            Value::Text(recipe.is_for_sale.to_string()),
+        ),
+        (
+            "recipe:is_community".to_string(),
+            Value::Text(recipe.is_community.to_string()),
+        ),
+        (
+            "recipe:owner_id".to_string(),
+            Value::Nat(Nat::from(recipe.user_id)),
+        ),
+    ];
+    // uploaded images stand in for recipes without an image url
+    let media = media::refs(recipe);
+    if let Some(image_url) = recipe
+        .image_url
+        .clone()
+        .or_else(|| media::cover_url(&media))
+    {
+        metadata.push(("icrc7:logo".to_string(), Value::Text(image_url)));
+    }
+    if !media.is_empty() {
+        metadata.push((
+            "recipe:media".to_string(),
+            Value::Array(
+                media
+                    .into_iter()
+                    .map(|media| {
+                        Value::Map(vec![
+                            ("url".to_string(), Value::Text(media.url)),
+                            ("content_type".to_string(), Value::Text(media.content_type)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+    }
+    // royalty terms, readable by marketplaces settling secondary sales
+    if let Some(creator_id) = recipe.creator_id {
+        metadata.push((
+            "recipe:creator_id".to_string(),
+            Value::Nat(Nat::from(creator_id)),
+        ));
+        if let Some(creator) = USER_STORAGE.with(|users| users.borrow().get(&creator_id)) {
+            metadata.push((
+                "recipe:royalty_recipient".to_string(),
+                Value::Blob(creator.principal.as_slice().to_vec()),
+            ));
+        }
+    }
+    metadata.push((
+        "recipe:royalty_bps".to_string(),
+        Value::Nat(Nat::from(recipe.royalty_bps.unwrap_or(0))),
+    ));
+    metadata.push((
+        "recipe:license".to_string(),
+        Value::Text(
+            match license_of(recipe) {
+                LicenseTier::Personal => "personal",
+                LicenseTier::Commercial => "commercial",
+            }
+            .to_string(),
+        ),
+    ));
+    if let Some(edition) = &recipe.edition {
+        metadata.push((
+            "recipe:edition_id".to_string(),
+            Value::Nat(Nat::from(edition.edition_id)),
+        ));
+        metadata.push((
+            "recipe:edition_number".to_string(),
+            Value::Nat(Nat::from(edition.number)),
+        ));
+        metadata.push((
+            "recipe:max_supply".to_string(),
+            Value::Nat(Nat::from(edition.max_supply)),
+        ));
+    }
+    metadata
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RoyaltyInfo {
+    receiver: Option<Account>,
+    royalty_amount: Nat,
+}
+
+// Get the royalty owed to a recipe's creator if it sells at a price, in the
+// style of EIP-2981 'royaltyInfo'
+#[ic_cdk::query]
+fn get_royalty_info(recipe_id: u64, sale_price: u64) -> Result<RoyaltyInfo, Error> {
+    match RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&recipe_id)) {
+        Some(recipe) => match royalty_for(&recipe, sale_price) {
+            Some((creator_id, royalty)) => Ok(RoyaltyInfo {
+                receiver: USER_STORAGE
+                    .with(|users| users.borrow().get(&creator_id))
+                    .map(|creator| creator.principal.into()),
+                royalty_amount: Nat::from(royalty),
+            }),
+            None => Ok(RoyaltyInfo {
+                receiver: None,
+                royalty_amount: Nat::from(0u64),
+            }),
+        },
+        None => Err(Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        }),
+    }
+}
+
+// Move a recipe from its current owner to another user, the recipe is delisted
+// and any approvals on it are revoked. Returns the block index of the transfer
+pub(crate) fn transfer_ownership(recipe: Recipe, to: &User) -> Result<u64, Error> {
+    Settlement::transfer(recipe, to.id).apply()
+}
+
+// The message a failed transfer is reported with, None when the caller was not
+// allowed to make it
+pub(crate) fn failure_message(error: Error) -> Option<String> {
+    match error {
+        Error::Unauthorized { .. } | Error::AnonymousCaller { .. } => None,
+        Error::NotFound { msg }
+        | Error::AlreadyInit { msg }
+        | Error::InvalidPayload { msg }
+        | Error::CallFailed { msg }
+        | Error::PaymentFailed { msg }
+        | Error::SettlementFailed { msg } => Some(msg),
+    }
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:symbol".to_string(), Value::Text(SYMBOL.to_string())),
+        ("icrc7:name".to_string(), Value::Text(NAME.to_string())),
+        (
+            "icrc7:description".to_string(),
+            Value::Text(DESCRIPTION.to_string()),
+        ),
+        (
+            "icrc7:total_supply".to_string(),
+            Value::Nat(icrc7_total_supply()),
+        ),
+        (
+            "icrc7:max_query_batch_size".to_string(),
+            Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE)),
+        ),
+        (
+            "icrc7:max_update_batch_size".to_string(),
+            Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE)),
+        ),
+        (
+            "icrc7:default_take_value".to_string(),
+            Value::Nat(Nat::from(DEFAULT_TAKE_VALUE)),
+        ),
+        (
+            "icrc7:max_take_value".to_string(),
+            Value::Nat(Nat::from(MAX_TAKE_VALUE)),
+        ),
+        (
+            "icrc7:max_memo_size".to_string(),
+            Value::Nat(Nat::from(MAX_MEMO_SIZE)),
+        ),
+        (
+            "icrc7:atomic_batch_transfers".to_string(),
+            Value::Text("false".to_string()),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    NAME.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some(DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(RECIPE_STORAGE.with(|recipes| recipes.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE as usize)
+        .map(|token_id| recipe_by_token(token_id).map(|recipe| recipe_metadata(&recipe)))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE as usize)
+        .map(|token_id| recipe_by_token(token_id).and_then(|recipe| recipe_owner_account(&recipe)))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE as usize)
+        .map(|account| match account_user(account) {
+            Some(user) => Nat::from(recipe_ids_of(&user).len()),
+            None => Nat::from(0u64),
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let ids: Vec<u64> =
+        RECIPE_STORAGE.with(|recipes| recipes.borrow().iter().map(|(id, _)| id).collect());
+    paginate(ids.into_iter(), prev, take)
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    match account_user(&account) {
+        Some(user) => paginate(recipe_ids_of(&user).into_iter(), prev, take),
+        None => vec![],
+    }
+}
+
+#[ic_cdk::update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    let caller = match authenticated_caller() {
+        Ok(caller) => caller,
+        Err(_) => return vec![Some(Err(TransferError::Unauthorized))],
+    };
+    if args.len() as u64 > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(1u64),
+            message: format!("At most {} transfers per batch", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(transfer_one(caller, arg)))
+        .collect()
+}
+
+// the deduplication key of a transfer: its created_at_time, so keys sort by
+// age, then the hash of the caller and the transfer
+fn transfer_key(created_at: u64, caller: &Principal, arg: &TransferArg) -> Blob<40> {
+    let hash = Sha256::new()
+        .chain_update(caller.as_slice())
+        .chain_update(Encode!(arg).unwrap())
+        .finalize();
+    let key = [created_at.to_be_bytes().as_slice(), hash.as_slice()].concat();
+    Blob::try_from(key.as_slice()).expect("Transfer keys are 40 bytes")
+}
+
+// drop transfers too old to be submitted again, they can not be duplicated
+fn forget_old_transfers() {
+    let oldest = ic_cdk::api::time().saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+    let expired: Vec<Blob<40>> = RECENT_TRANSFER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| u64::from_be_bytes(key.as_slice()[..8].try_into().unwrap()) < oldest)
+            .collect()
+    });
+    RECENT_TRANSFER_STORAGE.with(|s| {
+        let mut transfers = s.borrow_mut();
+        for key in expired {
+            transfers.remove(&key);
+        }
+    });
+}
+
+fn transfer_one(caller: Principal, arg: TransferArg) -> TransferResult {
+    if arg.memo.as_ref().map_or(0, |memo| memo.len()) as u64 > MAX_MEMO_SIZE {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(2u64),
+            message: format!("Memo is longer than {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    match check_created_at(arg.created_at_time) {
+        Err(TimeCheck::TooOld) => return Err(TransferError::TooOld),
+        Err(TimeCheck::CreatedInFuture { ledger_time }) => {
+            return Err(TransferError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => (),
+    }
+    let dedup_key = arg
+        .created_at_time
+        .map(|created_at| transfer_key(created_at, &caller, &arg));
+    if let Some(key) = &dedup_key {
+        forget_old_transfers();
+        if let Some(index) = RECENT_TRANSFER_STORAGE.with(|s| s.borrow().get(key)) {
+            return Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(index),
+            });
+        }
+    }
+    let recipe = recipe_by_token(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    };
+    let owner = account_user(&from).ok_or(TransferError::Unauthorized)?;
+    if owner.id != recipe.user_id {
+        return Err(TransferError::Unauthorized);
+    }
+    if recipe.is_community {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(3u64),
+            message: "Community recipes can not be transferred".to_string(),
+        });
+    }
+
+    let to = account_user(&arg.to).ok_or(TransferError::InvalidRecipient)?;
+    if to.id == owner.id {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    match transfer_ownership(recipe, &to) {
+        Ok(index) => {
+            if let Some(key) = dedup_key {
+                RECENT_TRANSFER_STORAGE.with(|s| s.borrow_mut().insert(key, index));
+            }
+            Ok(Nat::from(index))
+        }
+        Err(error) => match failure_message(error) {
+            Some(message) => Err(TransferError::GenericError {
+                error_code: Nat::from(4u64),
+                message,
+            }),
+            None => Err(TransferError::Unauthorized),
+        },
+    }
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-3".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
@@ -0,0 +1,131 @@
+use crate::icrc7::Account;
+use crate::{Error, CONTRACT_STORAGE};
+use candid::{Nat, Principal};
+
+// Arguments and errors of the ICRC-1 'icrc1_transfer' and ICRC-2
+// 'icrc2_transfer_from' ledger methods
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Get the ledger canister payments are settled on
+pub(crate) fn ledger_canister() -> Result<Principal, Error> {
+    match CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
+        Some(contract) => match contract.ledger_canister {
+            Some(ledger) => Ok(ledger),
+            None => Err(Error::NotFound {
+                msg: "No ledger canister has been configured".to_string(),
+            }),
+        },
+        None => Err(Error::NotFound {
+            msg: "Contract has not been initialized".to_string(),
+        }),
+    }
+}
+
+// Get the transfer fee charged by the ledger
+pub(crate) async fn fee(ledger: Principal) -> Result<u64, Error> {
+    let result: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_fee", ()).await;
+    match result {
+        Ok((fee,)) => u64::try_from(&fee.0).map_err(|_| Error::CallFailed {
+            msg: format!("Ledger fee is out of range: {}", fee),
+        }),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("Could not get ledger fee: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Pull tokens from a user's account into the marketplace's account using the
+// allowance they granted with 'icrc2_approve'
+pub(crate) async fn transfer_from(
+    ledger: Principal,
+    from: Principal,
+    amount: u64,
+) -> Result<Nat, Error> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to: ic_cdk::id().into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<(Result<Nat, TransferFromError>,), _> =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(e),)) => Err(Error::PaymentFailed {
+            msg: format!("Ledger rejected the transfer: {:?}", e),
+        }),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("Could not call icrc2_transfer_from: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Send tokens from the marketplace's account to a user
+pub(crate) async fn transfer(ledger: Principal, to: Principal, amount: u64) -> Result<Nat, Error> {
+    let args = TransferArg {
+        from_subaccount: None,
+        to: to.into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(e),)) => Err(Error::PaymentFailed {
+            msg: format!("Ledger rejected the transfer: {:?}", e),
+        }),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("Could not call icrc1_transfer: {:?} {}", code, msg),
+        }),
+    }
+}
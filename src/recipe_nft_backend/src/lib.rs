@@ -1,20 +1,156 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use auction::{Auction, BidPayload, StartAuctionPayload};
+use body::RecipeBody;
+use candid::{Decode, Encode, Nat, Principal};
+use content::{RecipeContent, RecipePreview};
+use dutch_auction::{DutchAuction, DutchAuctionPrice, StartDutchAuctionPayload};
+use edition::{Edition, EditionPayload, EditionSet};
+use encryption::{RecipeKey, SealedBody};
+use history::{
+    ArchiveInfo, BlockType, GetArchivesArgs, GetBlocksArgs, GetBlocksResult, HistoryPayload,
+    Transaction, TransactionKind,
+};
+use http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingToken};
+use ic_certification::{Hash, RbTree};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::{
+    storable::Blob, BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use icrc37::{
+    ApproveCollectionArg, ApproveCollectionResult, ApproveTokenArg, ApproveTokenResult,
+    CollectionApproval, IsApprovedArg, RevokeCollectionApprovalArg, RevokeCollectionApprovalResult,
+    RevokeTokenApprovalArg, RevokeTokenApprovalResult, TokenApproval, TransferFromArg,
+    TransferFromResult,
+};
+use icrc7::{Account, RoyaltyInfo, SupportedStandard, TransferArg, TransferResult, Value};
+use license::LicenseTier;
+use listing::{Listing, ListingPayload, ListingStatus};
+use media::{Media, MediaRef, MediaUploadPayload};
+use nutrition::{Allergen, Nutrition, NutritionFacts};
+use offer::{CounterOfferPayload, Offer, OfferPayload};
+use password::PasswordHash;
+use rental::{RentPayload, Rental, RentalPricePayload};
+use scaling::{ScaledRecipe, UnitSystem};
+use settlement::Settlement;
+use shopping::ShoppingList;
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+use subscription::{PlanPayload, Subscription, SubscriptionPlan};
 use validator::Validate;
 
+mod auction;
+mod body;
+mod content;
+mod dutch_auction;
+mod edition;
+mod encryption;
+mod history;
+mod http;
+mod icrc37;
+mod icrc7;
+mod ledger;
+mod license;
+mod listing;
+mod media;
+mod nutrition;
+mod offer;
+mod pages;
+mod password;
+mod rental;
+mod scaling;
+mod settlement;
+mod shopping;
+mod subscription;
+
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Contract {
     id: u64,
+    owner: Principal,
     email: String,
-    password: String,
+    password: PasswordHash,
+    ledger_canister: Option<Principal>,
+    // None until the owner first configures the marketplace
+    config: Option<MarketplaceConfig>,
+    // marketplace fees collected and not yet withdrawn
+    treasury_balance: Option<u64>,
+    // address pages and media are served at, https://<canister id>.icp0.io if
+    // None. Set it on local replicas and custom domains
+    site_url: Option<String>,
+}
+
+impl Contract {
+    fn config(&self) -> MarketplaceConfig {
+        self.config.clone().unwrap_or_default()
+    }
+}
+
+// Marketplace parameters set by the contract owner
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct MarketplaceConfig {
+    // share of every sale kept by the marketplace, in basis points
+    #[validate(range(max = 2500))]
+    fee_bps: u16,
+    // account treasury withdrawals are paid to, the contract owner if None
+    fee_recipient: Option<Principal>,
+    min_price: u64,
+    max_price: Option<u64>,
+    // categories recipes may be listed under, any category if empty
+    #[validate(length(max = 16))]
+    allowed_categories: Vec<String>,
+}
+
+impl MarketplaceConfig {
+    fn check_category(&self, category: &str) -> Result<(), Error> {
+        if !self.allowed_categories.is_empty()
+            && !self
+                .allowed_categories
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(category))
+        {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "Category: {} is not allowed, use one of: {}",
+                    category,
+                    self.allowed_categories.join(", ")
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // check the price of a recipe that is listed for sale
+    fn check_price(&self, price: u64) -> Result<(), Error> {
+        if price < self.min_price || self.max_price.is_some_and(|max| price > max) {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "Price must be between {} and {}",
+                    self.min_price,
+                    self.max_price
+                        .map_or("unlimited".to_string(), |max| max.to_string())
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+// Get the marketplace configuration, the defaults if the contract is not initialized
+fn marketplace_config() -> MarketplaceConfig {
+    CONTRACT_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .map(|contract| contract.config())
+        .unwrap_or_default()
+}
+
+// the address pages and media are served at, as set by the contract owner
+fn site_url() -> Option<String> {
+    CONTRACT_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .and_then(|contract| contract.site_url)
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -23,16 +159,54 @@ struct Recipe {
     title: String,
     category: String,
     description: String,
-    price: u32,
+    // 'price' and 'is_for_sale' mirror the recipe's active listing
+    price: u64,
     user_id: u64,
     is_community: bool,
     is_for_sale: bool,
     reviews: Vec<String>,
+    // author of the recipe, never changes on resale. None for recipes minted before royalties
+    creator_id: Option<u64>,
+    // share of every secondary sale paid to the creator, in basis points
+    royalty_bps: Option<u16>,
+    // the copy this token is of a limited edition, None for one-of-a-kind recipes
+    edition: Option<Edition>,
+    // the license the owner holds, None for recipes owned before license tiers
+    license: Option<LicenseTier>,
+    // daily price the owner rents the recipe out for, None when it can not be rented
+    rental_price_per_day: Option<u64>,
+    // public short description shown before the recipe is bought
+    teaser: Option<String>,
+    image_url: Option<String>,
+    // None for recipes added before structured recipes
+    servings: Option<u32>,
+    prep_minutes: Option<u32>,
+    cook_minutes: Option<u32>,
+    // per serving, computed from the ingredient nutrition table
+    nutrition: Option<Nutrition>,
+    // ids of the images and videos uploaded for the recipe, in order
+    media: Option<Vec<u64>>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct User {
     id: u64,
+    principal: Principal,
+    name: String,
+    password: PasswordHash,
+    email: String,
+    balance: u64,
+    recipes: Vec<u64>,
+    // X25519 public key recipe keys are wrapped for, None until the user sets one
+    encryption_key: Option<Vec<u8>>,
+}
+
+// Shapes of 'User' and 'Contract' records written before password hashing,
+// decoded as a fallback and migrated in 'post_upgrade'
+#[derive(candid::CandidType, Deserialize)]
+struct UserV0 {
+    id: u64,
+    principal: Option<Principal>,
     name: String,
     password: String,
     email: String,
@@ -40,49 +214,139 @@ struct User {
     recipes: Vec<u64>,
 }
 
+#[derive(candid::CandidType, Deserialize)]
+struct ContractV0 {
+    id: u64,
+    owner: Option<Principal>,
+    email: String,
+    password: String,
+}
+
+// Shape of 'Recipe' records written before prices were widened to u64 for
+// ledger token amounts
+#[derive(candid::CandidType, Deserialize)]
+struct RecipeV0 {
+    id: u64,
+    title: String,
+    category: String,
+    description: String,
+    price: u32,
+    user_id: u64,
+    is_community: bool,
+    is_for_sale: bool,
+    reviews: Vec<String>,
+}
+
+impl From<UserV0> for User {
+    fn from(user: UserV0) -> Self {
+        User {
+            id: user.id,
+            principal: user.principal.unwrap_or_else(Principal::anonymous),
+            name: user.name,
+            password: PasswordHash::legacy(user.password),
+            email: user.email,
+            balance: user.balance.into(),
+            recipes: user.recipes,
+            encryption_key: None,
+        }
+    }
+}
+
+impl From<RecipeV0> for Recipe {
+    fn from(recipe: RecipeV0) -> Self {
+        Recipe {
+            id: recipe.id,
+            title: recipe.title,
+            category: recipe.category,
+            description: recipe.description,
+            price: recipe.price.into(),
+            user_id: recipe.user_id,
+            is_community: recipe.is_community,
+            is_for_sale: recipe.is_for_sale,
+            reviews: recipe.reviews,
+            creator_id: None,
+            royalty_bps: None,
+            edition: None,
+            license: None,
+            rental_price_per_day: None,
+            teaser: None,
+            image_url: None,
+            servings: None,
+            prep_minutes: None,
+            cook_minutes: None,
+            nutrition: None,
+            media: None,
+        }
+    }
+}
+
+impl From<ContractV0> for Contract {
+    fn from(contract: ContractV0) -> Self {
+        Contract {
+            id: contract.id,
+            owner: contract.owner.unwrap_or_else(Principal::anonymous),
+            email: contract.email,
+            password: PasswordHash::legacy(contract.password),
+            ledger_canister: None,
+            config: None,
+            treasury_balance: None,
+            site_url: None,
+        }
+    }
+}
+
 // Implement the 'Storable' trait for 'Recipe', 'User' and 'CommunityRecipe'
 
 impl Storable for User {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
-    // Conversion from bytes
+    // Conversion from bytes, falling back to the pre-hashing shape
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match Decode!(bytes.as_ref(), Self) {
+            Ok(user) => user,
+            Err(_) => Decode!(bytes.as_ref(), UserV0).unwrap().into(),
+        }
     }
 }
 
 impl Storable for Recipe {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
-    // Conversion from bytes
+    // Conversion from bytes, falling back to the u32 price shape
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match Decode!(bytes.as_ref(), Self) {
+            Ok(recipe) => recipe,
+            Err(_) => Decode!(bytes.as_ref(), RecipeV0).unwrap().into(),
+        }
     }
 }
 
 impl Storable for Contract {
     // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
-    // Conversion from bytes
+    // Conversion from bytes, falling back to the legacy plaintext password shape
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match Decode!(bytes.as_ref(), Self) {
+            Ok(contract) => contract,
+            Err(_) => Decode!(bytes.as_ref(), ContractV0).unwrap().into(),
+        }
     }
 }
 
 // Implement the 'BoundedStorable' trait for 'Recipe', 'User' and 'CommunityRecipe'
 impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 1024;
+    const MAX_SIZE: u32 = 16 * 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
 impl BoundedStorable for Recipe {
-    const MAX_SIZE: u32 = 1024;
+    const MAX_SIZE: u32 = 16 * 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
@@ -91,6 +355,42 @@ impl BoundedStorable for Contract {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// A record of a map created with a 1024 byte bound. Stable maps can not
+// raise their bound, so users and recipes are copied out of these maps to
+// larger ones in 'post_upgrade'
+struct Legacy<T>(T);
+
+impl<T: Storable> Storable for Legacy<T> {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Legacy(T::from_bytes(bytes))
+    }
+}
+
+impl<T: Storable> BoundedStorable for Legacy<T> {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// reject a record that would encode past the bound of its map, which would
+// trap the call writing it
+fn check_size<T: BoundedStorable>(record: &T, name: &str) -> Result<(), Error> {
+    if record.to_bytes().len() > T::MAX_SIZE as usize {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "The {} is too large to store, it must encode to at most {} bytes",
+                name,
+                T::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
 // Define thread-local static variables for memory management and storage
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -102,20 +402,144 @@ thread_local! {
             .expect("Cannot create a counter")
     );
 
-    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+    static LEGACY_USER_STORAGE: RefCell<StableBTreeMap<u64, Legacy<User>, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
 
-    static RECIPE_STORAGE: RefCell<StableBTreeMap<u64, Recipe, Memory>> =
+    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // the id of the user bound to each principal, anonymous ones aside
+    static USER_BY_PRINCIPAL: RefCell<StableBTreeMap<Blob<29>, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    static LEGACY_RECIPE_STORAGE: RefCell<StableBTreeMap<u64, Legacy<Recipe>, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
     ));
 
+    static RECIPE_STORAGE: RefCell<StableBTreeMap<u64, Recipe, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
     static CONTRACT_STORAGE: RefCell<StableBTreeMap<u64, Contract, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
     ));
+
+    static APPROVAL_STORAGE: RefCell<StableBTreeMap<u64, icrc37::Approval, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static HISTORY_STORAGE: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static AUCTION_STORAGE: RefCell<StableBTreeMap<u64, Auction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static DUTCH_AUCTION_STORAGE: RefCell<StableBTreeMap<u64, DutchAuction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static OFFER_STORAGE: RefCell<StableBTreeMap<u64, Offer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static LISTING_STORAGE: RefCell<StableBTreeMap<u64, Listing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // the id of each listed recipe's active listing
+    static ACTIVE_LISTING_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // set to 1 once recipes marked for sale by earlier versions were listed
+    static LISTINGS_MIGRATED: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 0)
+            .expect("Cannot create the listing migration flag")
+    );
+
+    static EDITION_STORAGE: RefCell<StableBTreeMap<u64, EditionSet, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static RENTAL_STORAGE: RefCell<StableBTreeMap<u64, Rental, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static PLAN_STORAGE: RefCell<StableBTreeMap<u64, SubscriptionPlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static RECIPE_BODY_STORAGE: RefCell<StableBTreeMap<u64, SealedBody, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static RECIPE_KEY_STORAGE: RefCell<StableBTreeMap<u64, RecipeKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static NUTRITION_STORAGE: RefCell<StableBTreeMap<u64, NutritionFacts, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // end of the bytes written to the media region
+    static MEDIA_CURSOR: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 0)
+            .expect("Cannot create the media cursor")
+    );
+
+    // raw bytes of uploaded images and videos, appended one after another
+    static MEDIA_REGION: Memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)));
+
+    static MEDIA_STORAGE: RefCell<StableBTreeMap<u64, Media, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // extents of the media region freed by deleted or expired media, by offset
+    static FREE_MEDIA_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // block index of each recent ICRC-7 transfer that gave a created_at_time,
+    // keyed by that time followed by the hash of the caller and transfer
+    static RECENT_TRANSFER_STORAGE: RefCell<StableBTreeMap<Blob<40>, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // hashes of the pages and media served over http, kept on the heap and
+    // certified again after an upgrade
+    static CERTIFIED_ASSETS: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
 }
 
 // Struct for payload date used in update functions
@@ -126,10 +550,25 @@ struct RecipePayload {
     category: String,
     #[validate(length(min = 6))]
     description: String,
+    #[validate(length(max = 280))]
+    teaser: String,
+    #[validate(url)]
+    image_url: Option<String>,
+    #[validate]
+    body: RecipeBody,
+    #[validate(range(min = 1, max = 100))]
+    servings: u32,
+    // a day at most
+    #[validate(range(max = 1440))]
+    prep_minutes: u32,
+    #[validate(range(max = 1440))]
+    cook_minutes: u32,
     is_community: bool,
     is_for_sale: bool,
-    price: u32,
-    owner_id: u64,
+    price: u64,
+    // at most 50% of secondary sales can go to the creator
+    #[validate(range(max = 5000))]
+    royalty_bps: u16,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
@@ -139,6 +578,63 @@ struct UserPayload {
     #[validate(length(min = 3))]
     password: String,
     email: String,
+    encryption_key: Option<Vec<u8>>,
+}
+
+// Filters for searching recipes, every filter given must match. Recipes
+// without nutrition never match a nutrition filter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RecipeFilter {
+    // matched against category and title
+    search: Option<String>,
+    max_calories: Option<f64>,
+    min_protein_g: Option<f64>,
+    max_carbs_g: Option<f64>,
+    max_fat_g: Option<f64>,
+    exclude_allergens: Vec<Allergen>,
+}
+
+impl RecipeFilter {
+    fn has_nutrition_filter(&self) -> bool {
+        self.max_calories.is_some()
+            || self.min_protein_g.is_some()
+            || self.max_carbs_g.is_some()
+            || self.max_fat_g.is_some()
+            || !self.exclude_allergens.is_empty()
+    }
+
+    fn matches(&self, recipe: &Recipe) -> bool {
+        if let Some(search) = &self.search {
+            let query = search.to_lowercase();
+            if !recipe.category.to_lowercase().contains(&query)
+                && !recipe.title.to_lowercase().contains(&query)
+            {
+                return false;
+            }
+        }
+        if !self.has_nutrition_filter() {
+            return true;
+        }
+        let Some(nutrition) = &recipe.nutrition else {
+            return false;
+        };
+        // recipes with ingredients missing from the table may hold unknown allergens
+        let allergen_free = self.exclude_allergens.is_empty()
+            || (nutrition.is_complete
+                && !nutrition
+                    .allergens
+                    .iter()
+                    .any(|a| self.exclude_allergens.contains(a)));
+        allergen_free
+            && self
+                .max_calories
+                .is_none_or(|max| nutrition.calories <= max)
+            && self
+                .min_protein_g
+                .is_none_or(|min| nutrition.protein_g >= min)
+            && self.max_carbs_g.is_none_or(|max| nutrition.carbs_g <= max)
+            && self.max_fat_g.is_none_or(|max| nutrition.fat_g <= max)
+    }
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -155,137 +651,518 @@ struct InitPayload {
     password: String,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+// the teaser, image, body, servings and times are left unchanged when not given
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct EditRecipePayload {
     recipe_id: u64,
     title: String,
     description: String,
     is_community: bool,
-    is_for_sale: bool,
-    price: u32,
-    password: String,
+    #[validate(length(max = 280))]
+    teaser: Option<String>,
+    #[validate(url)]
+    image_url: Option<String>,
+    #[validate]
+    body: Option<RecipeBody>,
+    #[validate(range(min = 1, max = 100))]
+    servings: Option<u32>,
+    #[validate(range(max = 1440))]
+    prep_minutes: Option<u32>,
+    #[validate(range(max = 1440))]
+    cook_minutes: Option<u32>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct EditCommunityRecipe {
     recipe_id: u64,
-    user_id: u64,
     description: String,
+    // the body is left unchanged when not given
+    #[validate]
+    body: Option<RecipeBody>,
 }
 
 // Structs for return methods
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct ReturnUser {
     id: u64,
+    principal: Principal,
     name: String,
     email: String,
     recipes: Vec<u64>,
-    balance: u32,
+    balance: u64,
+    encryption_key: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BuyNftPayload {
+    recipe_id: u64,
+    tier: LicenseTier,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnContract {
+    id: u64,
+    owner: Principal,
+    email: String,
+    ledger_canister: Option<Principal>,
+    site_url: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct SiteUrlPayload {
+    #[validate(url, length(max = 100))]
+    site_url: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct ChangePasswordPayload {
+    old_password: String,
+    #[validate(length(min = 4))]
+    new_password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct ResetPasswordPayload {
+    user_id: u64,
+    #[validate(length(min = 4))]
+    new_password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClaimUserPayload {
+    user_id: u64,
+    password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClaimContractPayload {
+    password: String,
+}
+
+impl From<Contract> for ReturnContract {
+    fn from(contract: Contract) -> Self {
+        ReturnContract {
+            id: contract.id,
+            owner: contract.owner,
+            email: contract.email,
+            ledger_canister: contract.ledger_canister,
+            site_url: contract.site_url,
+        }
+    }
+}
+
+impl From<User> for ReturnUser {
+    fn from(user: User) -> Self {
+        ReturnUser {
+            id: user.id,
+            principal: user.principal,
+            name: user.name,
+            email: user.email,
+            recipes: user.recipes,
+            balance: user.balance,
+            encryption_key: user.encryption_key,
+        }
+    }
+}
+
+// Get the caller's principal, anonymous principals are rejected
+fn authenticated_caller() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::AnonymousCaller {
+            msg: "Anonymous principals are not allowed, please sign in".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// the key of a principal in USER_BY_PRINCIPAL
+fn principal_key(principal: &Principal) -> Blob<29> {
+    Blob::try_from(principal.as_slice()).expect("Principals are at most 29 bytes")
+}
+
+// Get the user bound to a principal
+fn user_by_principal(principal: Principal) -> Option<User> {
+    let id = USER_BY_PRINCIPAL.with(|index| index.borrow().get(&principal_key(&principal)))?;
+    USER_STORAGE.with(|users| users.borrow().get(&id))
+}
+
+// bind a user's principal to them in the index, unclaimed users have none
+fn index_principal(user: &User) {
+    if user.principal != Principal::anonymous() {
+        USER_BY_PRINCIPAL.with(|index| {
+            index
+                .borrow_mut()
+                .insert(principal_key(&user.principal), user.id)
+        });
+    }
+}
+
+// Get the user bound to the caller's principal
+fn caller_user() -> Result<User, Error> {
+    let caller = authenticated_caller()?;
+    match user_by_principal(caller) {
+        Some(user) => Ok(user),
+        None => Err(Error::Unauthorized {
+            msg: format!("No user is registered for principal: {}", caller),
+        }),
+    }
+}
+
+// Get the contract, only if the caller is the contract owner
+fn caller_contract_owner() -> Result<Contract, Error> {
+    let caller = authenticated_caller()?;
+    match CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
+        Some(contract) => {
+            if contract.owner != caller {
+                return Err(Error::Unauthorized {
+                    msg: "Unauthorized, only the contract owner can perform this action"
+                        .to_string(),
+                });
+            }
+            Ok(contract)
+        }
+        None => Err(Error::NotFound {
+            msg: "Contract has not been initialized".to_string(),
+        }),
+    }
+}
+
+// update function to init contract, the caller becomes the contract owner
+#[ic_cdk::update]
+async fn init_contract(payload: InitPayload) -> Result<ReturnContract, Error> {
+    // validate payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let owner = authenticated_caller()?;
+    let salt = password::new_salt().await?;
+
+    let contract = CONTRACT_STORAGE.with(|s| s.borrow().get(&0));
+    if contract.is_some() {
+        return Err(Error::AlreadyInit {
+            msg: "Contract has already been initialized".to_string(),
+        });
+    }
+
+    let contract = Contract {
+        id: 0,
+        owner,
+        email: payload.email,
+        password: PasswordHash::new(&payload.password, salt),
+        ledger_canister: None,
+        config: None,
+        treasury_balance: None,
+        site_url: None,
+    };
+
+    match CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, contract.clone())) {
+        Some(_) => Err(Error::InvalidPayload {
+            msg: format!("Could not add recipe email: {}", contract.email),
+        }),
+        None => Ok(contract.into()),
+    }
+}
+
+// update function for the contract owner to change the contract password
+#[ic_cdk::update]
+async fn change_contract_password(payload: ChangePasswordPayload) -> Result<String, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let contract = caller_contract_owner()?;
+    if !contract.password.verify(&payload.old_password) {
+        return Err(Error::Unauthorized {
+            msg: "Invalid password please try again".to_string(),
+        });
+    }
+    let salt = password::new_salt().await?;
+
+    // re-read the contract, it may have changed while waiting for the salt
+    let contract = caller_contract_owner()?;
+    let new_contract = Contract {
+        password: PasswordHash::new(&payload.new_password, salt),
+        ..contract
+    };
+    CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract));
+    Ok("Contract password changed successfully".to_string())
+}
+
+// update function to bind a contract created before principal authentication to the caller
+#[ic_cdk::update]
+fn claim_contract(payload: ClaimContractPayload) -> Result<ReturnContract, Error> {
+    let owner = authenticated_caller()?;
+    match CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
+        Some(contract) => {
+            if contract.owner != Principal::anonymous() {
+                return Err(Error::AlreadyInit {
+                    msg: "Contract owner has already been set".to_string(),
+                });
+            }
+            if !contract.password.verify(&payload.password) {
+                return Err(Error::Unauthorized {
+                    msg: "Invalid password please try again".to_string(),
+                });
+            }
+            let new_contract = Contract { owner, ..contract };
+            CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract.clone()));
+            Ok(new_contract.into())
+        }
+        None => Err(Error::NotFound {
+            msg: "Contract has not been initialized".to_string(),
+        }),
+    }
+}
+
+// update function for the contract owner to set the ICRC-1/ICRC-2 ledger payments settle on
+#[ic_cdk::update]
+fn set_ledger_canister(ledger: Principal) -> Result<ReturnContract, Error> {
+    let contract = caller_contract_owner()?;
+    let new_contract = Contract {
+        ledger_canister: Some(ledger),
+        ..contract
+    };
+    CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract.clone()));
+    Ok(new_contract.into())
+}
+
+// update function for the contract owner to change the marketplace configuration
+#[ic_cdk::update]
+fn update_marketplace_config(config: MarketplaceConfig) -> Result<MarketplaceConfig, Error> {
+    if let Err(e) = config.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if config
+        .allowed_categories
+        .iter()
+        .any(|category| category.is_empty() || category.len() > 30)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Categories must be 1 to 30 characters long".to_string(),
+        });
+    }
+    if config.max_price.is_some_and(|max| max < config.min_price) {
+        return Err(Error::InvalidPayload {
+            msg: "max_price can not be lower than min_price".to_string(),
+        });
+    }
+    let contract = caller_contract_owner()?;
+    let new_contract = Contract {
+        config: Some(config.clone()),
+        ..contract
+    };
+    check_size(&new_contract, "marketplace configuration")?;
+    CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract));
+    Ok(config)
+}
+
+// update function for the contract owner to set the address pages and media
+// are served at. Every page links to it, so all of them are certified again
+#[ic_cdk::update]
+fn set_site_url(payload: SiteUrlPayload) -> Result<ReturnContract, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let contract = caller_contract_owner()?;
+    let new_contract = Contract {
+        site_url: payload.site_url,
+        ..contract
+    };
+    check_size(&new_contract, "contract")?;
+    CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract.clone()));
+    pages::certify_all();
+    Ok(new_contract.into())
+}
+
+// query function to get the marketplace configuration
+#[ic_cdk::query]
+fn get_marketplace_config() -> MarketplaceConfig {
+    marketplace_config()
+}
+
+// query function for the contract owner to get the fees collected in the treasury
+#[ic_cdk::query]
+fn get_treasury_balance() -> Result<u64, Error> {
+    let contract = caller_contract_owner()?;
+    Ok(contract.treasury_balance.unwrap_or(0))
+}
+
+// update function for the contract owner to withdraw collected fees to the fee
+// recipient's ledger account, the ledger fee is taken from the withdrawn amount
+#[ic_cdk::update]
+async fn withdraw_treasury(amount: u64) -> Result<u64, Error> {
+    caller_contract_owner()?;
+    let ledger = ledger::ledger_canister()?;
+    let fee = ledger::fee(ledger).await?;
+    if amount <= fee {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Withdrawal amount must be greater than the ledger fee: {}",
+                fee
+            ),
+        });
+    }
+
+    // debit before calling the ledger so the treasury can't be spent twice meanwhile
+    let contract = caller_contract_owner()?;
+    let treasury_balance = contract.treasury_balance.unwrap_or(0);
+    if treasury_balance < amount {
+        return Err(Error::InvalidPayload {
+            msg: "The treasury does not have enough balance".to_string(),
+        });
+    }
+    let recipient = contract.config().fee_recipient.unwrap_or(contract.owner);
+    set_treasury_balance(treasury_balance - amount);
+
+    match ledger::transfer(ledger, recipient, amount - fee).await {
+        Ok(_) => {
+            history::record(
+                TransactionKind::TreasuryWithdraw,
+                None,
+                None,
+                None,
+                Some(amount),
+            );
+            Ok(treasury_balance - amount)
+        }
+        Err(e) => {
+            let contract = caller_contract_owner()?;
+            set_treasury_balance(contract.treasury_balance.unwrap_or(0) + amount);
+            Err(e)
+        }
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct BuyNftPayload {
-    recipe_id: u64,
-    user_id: u64,
-    password: String,
+fn set_treasury_balance(balance: u64) {
+    CONTRACT_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        if let Some(contract) = storage.get(&0) {
+            storage.insert(
+                0,
+                Contract {
+                    treasury_balance: Some(balance),
+                    ..contract
+                },
+            );
+        }
+    });
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct FundUser {
-    user_id: u64,
-    amount: u32,
-    password: String,
+// update function to deposit ledger tokens into the caller's marketplace balance.
+// The caller must first approve the marketplace canister with 'icrc2_approve'
+#[ic_cdk::update]
+async fn deposit(amount: u64) -> Result<ReturnUser, Error> {
+    let user = caller_user()?;
+    let ledger = ledger::ledger_canister()?;
+
+    ledger::transfer_from(ledger, user.principal, amount).await?;
+    fund_user(user.id, amount)
 }
 
-// update function to init contract
+// update function to withdraw tokens from the caller's marketplace balance to
+// their ledger account, the ledger fee is taken from the withdrawn amount
 #[ic_cdk::update]
-fn init_contract(payload: InitPayload) -> Result<Contract, Error> {
-    // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+async fn withdraw(amount: u64) -> Result<ReturnUser, Error> {
+    let user = caller_user()?;
+    let ledger = ledger::ledger_canister()?;
+    let fee = ledger::fee(ledger).await?;
+    if amount <= fee {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!(
+                "Withdrawal amount must be greater than the ledger fee: {}",
+                fee
+            ),
         });
     }
 
-    let contract = CONTRACT_STORAGE.with(|s| s.borrow().get(&0));
-    if contract.is_some() {
-        return Err(Error::AlreadyInit {
-            msg: "Contract has already been initialized".to_string(),
-        });
+    // debit before calling the ledger so the balance can't be spent twice meanwhile
+    let debited = debit_balance(user.id, amount)?;
+    match ledger::transfer(ledger, debited.principal, amount - fee).await {
+        Ok(_) => {
+            history::record(
+                TransactionKind::Withdraw,
+                None,
+                Some(user.id),
+                None,
+                Some(amount),
+            );
+            Ok(debited.into())
+        }
+        Err(e) => {
+            credit_balance(user.id, amount)?;
+            Err(e)
+        }
     }
+}
 
-    let contract = Contract {
-        id: 0,
-        email: payload.email,
-        password: payload.password,
-    };
+// credit tokens received on the ledger to a user's marketplace balance
+fn fund_user(user_id: u64, amount: u64) -> Result<ReturnUser, Error> {
+    let user = credit_balance(user_id, amount)?;
+    history::record(
+        TransactionKind::Fund,
+        None,
+        None,
+        Some(user_id),
+        Some(amount),
+    );
+    Ok(user)
+}
 
-    match CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, contract.clone())) {
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("Could not add recipe email: {}", contract.email),
+// add tokens to a user's marketplace balance
+fn credit_balance(user_id: u64, amount: u64) -> Result<ReturnUser, Error> {
+    match USER_STORAGE.with(|u| u.borrow().get(&user_id)) {
+        Some(user) => {
+            let new_user = User {
+                balance: user.balance + amount,
+                ..user
+            };
+            check_size(&new_user, "user")?;
+            USER_STORAGE.with(|u| u.borrow_mut().insert(new_user.id, new_user.clone()));
+            Ok(new_user.into())
+        }
+        None => Err(Error::NotFound {
+            msg: "User could not be found".to_string(),
         }),
-        None => Ok(contract),
     }
 }
 
-// update function to fund user
-#[ic_cdk::update]
-fn fund_user(payload: FundUser) -> Result<ReturnUser, Error> {
-    let contract = CONTRACT_STORAGE.with(|s| s.borrow().get(&0));
-    match contract {
-        Some(contract) => {
-            if contract.password != payload.password {
-                return Err(Error::NotFound {
-                    msg: "Invalid password please try again".to_string(),
+// take tokens from a user's marketplace balance
+fn debit_balance(user_id: u64, amount: u64) -> Result<User, Error> {
+    match USER_STORAGE.with(|u| u.borrow().get(&user_id)) {
+        Some(user) => {
+            if user.balance < amount {
+                return Err(Error::InvalidPayload {
+                    msg: "You do not have enough balance".to_string(),
                 });
             }
-
-            let user = USER_STORAGE.with(|u| u.borrow_mut().get(&payload.user_id));
-
-            match user {
-                Some(user) => {
-                    let new_user = User {
-                        id: user.id.clone(),
-                        balance: user.balance + payload.amount,
-                        ..user
-                    };
-
-                    match USER_STORAGE.with(|u| u.borrow_mut().insert(user.id, new_user.clone())) {
-                        Some(_) => Ok(ReturnUser {
-                            id: new_user.id,
-                            name: new_user.name,
-                            email: new_user.email,
-                            recipes: new_user.recipes,
-                            balance: new_user.balance,
-                        }),
-                        None => Err(Error::NotFound {
-                            msg: "Could not fund user balance".to_string(),
-                        }),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: "User could not be found".to_string(),
-                }),
-            }
+            let new_user = User {
+                balance: user.balance - amount,
+                ..user
+            };
+            check_size(&new_user, "user")?;
+            USER_STORAGE.with(|u| u.borrow_mut().insert(new_user.id, new_user.clone()));
+            Ok(new_user)
         }
         None => Err(Error::NotFound {
-            msg: "Contract has not been initialized".to_string(),
+            msg: "User could not be found".to_string(),
         }),
     }
 }
 
 // Query function to get all recipes
 #[ic_cdk::query]
-fn get_all_recipes() -> Result<Vec<Recipe>, Error> {
+fn get_all_recipes() -> Result<Vec<RecipePreview>, Error> {
     // Retrieve all Recipes from the storage
     let recipe_map: Vec<(u64, Recipe)> = RECIPE_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Recipes from the tuple and create a vector
-    let recipes: Vec<Recipe> = recipe_map.into_iter().map(|(_, recipe)| recipe).collect();
+    // Extract the Recipe previews from the tuple and create a vector
+    let recipes: Vec<RecipePreview> = recipe_map
+        .into_iter()
+        .map(|(_, recipe)| recipe.into())
+        .collect();
 
     match recipes.len() {
         0 => Err(Error::NotFound {
-            msg: format!("no Recipes found"),
+            msg: "no Recipes found".to_string(),
         }),
         _ => Ok(recipes),
     }
@@ -293,30 +1170,26 @@ fn get_all_recipes() -> Result<Vec<Recipe>, Error> {
 
 // Query function to get all for sale recipes
 #[ic_cdk::query]
-fn get_all_for_sale_recipes() -> Result<Vec<Recipe>, Error> {
-    // Retrieve all Recipes from the storage
-    let recipe_map: Vec<(u64, Recipe)> = RECIPE_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Recipes from the tuple and create a vector
-    let recipes: Vec<Recipe> = recipe_map.into_iter().map(|(_, recipe)| recipe).collect();
-
-    // Filter the recipes by category
-    let recipes_by_category: Vec<Recipe> = recipes
+fn get_all_for_sale_recipes() -> Result<Vec<RecipePreview>, Error> {
+    // Look up the recipe of every active listing
+    let recipes: Vec<RecipePreview> = listing::active_listings()
         .into_iter()
-        .filter(|recipe| recipe.is_for_sale && !recipe.is_community)
+        .filter_map(|listing| RECIPE_STORAGE.with(|s| s.borrow().get(&listing.recipe_id)))
+        .map(RecipePreview::from)
         .collect();
 
     // Check if any recipes are found
-    match recipes_by_category.len() {
+    match recipes.len() {
         0 => Err(Error::NotFound {
-            msg: format!("No recipes up for sale could be found"),
+            msg: "No recipes up for sale could be found".to_string(),
         }),
-        _ => Ok(recipes_by_category),
+        _ => Ok(recipes),
     }
 }
 
 // Get Recipes by category and title content
 #[ic_cdk::query]
-fn get_recipe_by_category(search: String) -> Result<Vec<Recipe>, Error> {
+fn get_recipe_by_category(search: String) -> Result<Vec<RecipePreview>, Error> {
     let query = search.to_lowercase();
     // Retrieve all Recipes from the storage
     let recipe_map: Vec<(u64, Recipe)> = RECIPE_STORAGE.with(|s| s.borrow().iter().collect());
@@ -324,12 +1197,13 @@ fn get_recipe_by_category(search: String) -> Result<Vec<Recipe>, Error> {
     let recipes: Vec<Recipe> = recipe_map.into_iter().map(|(_, recipe)| recipe).collect();
 
     // Filter the recipes by category
-    let recipes_by_category: Vec<Recipe> = recipes
+    let recipes_by_category: Vec<RecipePreview> = recipes
         .into_iter()
         .filter(|recipe| {
             (recipe.category).to_lowercase().contains(&query)
                 || (recipe.title).to_lowercase().contains(&query)
         })
+        .map(RecipePreview::from)
         .collect();
 
     // Check if any recipes are found
@@ -341,27 +1215,52 @@ fn get_recipe_by_category(search: String) -> Result<Vec<Recipe>, Error> {
     }
 }
 
+// Search recipes by title, category, nutrition and allergens
+#[ic_cdk::query]
+fn search_recipes(filter: RecipeFilter) -> Result<Vec<RecipePreview>, Error> {
+    let recipes: Vec<RecipePreview> = RECIPE_STORAGE.with(|recipes| {
+        recipes
+            .borrow()
+            .iter()
+            .map(|(_, recipe)| recipe)
+            .filter(|recipe| filter.matches(recipe))
+            .map(RecipePreview::from)
+            .collect()
+    });
+
+    match recipes.len() {
+        0 => Err(Error::NotFound {
+            msg: "no recipes match the filter".to_string(),
+        }),
+        _ => Ok(recipes),
+    }
+}
+
 // get recipe by ID
 #[ic_cdk::query]
-fn get_recipe_by_id(id: u64) -> Result<Recipe, Error> {
+fn get_recipe_by_id(id: u64) -> Result<RecipePreview, Error> {
     match RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&id)) {
-        Some(recipe) => Ok(recipe),
+        Some(recipe) => Ok(recipe.into()),
         None => Err(Error::NotFound {
             msg: format!("recipe of id: {} not found", id),
         }),
     }
 }
 
-// Create new Recipe
+// Create new Recipe owned by the calling user
 #[ic_cdk::update]
-fn add_recipe(payload: RecipePayload) -> Result<Recipe, Error> {
+async fn add_recipe(payload: RecipePayload) -> Result<Recipe, Error> {
     // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let owner = caller_user()?;
+    let config = marketplace_config();
+    config.check_category(&payload.category)?;
+    if payload.is_for_sale && !payload.is_community {
+        config.check_price(payload.price)?;
     }
+    let key = encryption::random_bytes().await?;
 
     let id = ID_COUNTER
         .with(|counter| {
@@ -382,26 +1281,50 @@ fn add_recipe(payload: RecipePayload) -> Result<Recipe, Error> {
         description: payload.description,
         category: payload.category,
         is_community: payload.is_community,
-        is_for_sale: payload.is_for_sale,
+        is_for_sale: false,
         price,
-        user_id: payload.owner_id,
+        user_id: owner.id,
         reviews: vec![],
+        creator_id: Some(owner.id),
+        royalty_bps: Some(payload.royalty_bps),
+        edition: None,
+        license: Some(LicenseTier::Commercial),
+        rental_price_per_day: None,
+        teaser: Some(payload.teaser),
+        image_url: payload.image_url,
+        servings: Some(payload.servings),
+        prep_minutes: Some(payload.prep_minutes),
+        cook_minutes: Some(payload.cook_minutes),
+        nutrition: Some(nutrition::compute(&payload.body, payload.servings)),
+        media: None,
     };
+    check_size(&recipe, "recipe")?;
 
-    // add recipe to user
-    let user_id = payload.owner_id;
-    let recipe_id = id;
+    // keep the ingredients and steps apart from the public recipe, encrypted
+    // under a key of its own
+    encryption::create_key(id, key);
+    content::store_body(id, payload.body)?;
 
-    match add_recipe_to_owner(user_id, recipe_id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
-    }
+    // add recipe to user
+    add_recipe_to_owner(owner.id, id)?;
 
     match RECIPE_STORAGE.with(|s| s.borrow_mut().insert(id, recipe.clone())) {
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add recipe title: {}", payload.title),
         }),
-        None => Ok(recipe),
+        None => {
+            history::record(TransactionKind::Mint, Some(id), None, Some(owner.id), None);
+            pages::certify_recipe(id);
+            if payload.is_for_sale && !recipe.is_community {
+                let expires_at = ic_cdk::api::time() + listing::DEFAULT_LISTING_DURATION;
+                listing::create(&recipe, price, None, expires_at);
+                return Ok(Recipe {
+                    is_for_sale: true,
+                    ..recipe
+                });
+            }
+            Ok(recipe)
+        }
     }
 }
 
@@ -416,72 +1339,106 @@ fn add_recipe_to_owner(user_id: u64, recipe_id: u64) -> Result<(), Error> {
                 recipes: new_user_recipes,
                 ..user
             };
+            check_size(&new_user, "user")?;
             // update user in storage
             match USER_STORAGE.with(|s| s.borrow_mut().insert(user.id, new_user.clone())) {
                 None => Err(Error::InvalidPayload {
-                    msg: format!("Could not update user recipes"),
+                    msg: "Could not update user recipes".to_string(),
                 }),
 
                 Some(_) => Ok(()),
             }
         }
         None => Err(Error::NotFound {
-            msg: format!("Could not find recipe Buyer"),
+            msg: "Could not find recipe Buyer".to_string(),
         }),
     }
 }
 
-// update function to edit a recipe where only owners of recipes can edit title, is_community, price and description. Non owners can only edit descriptions of communtiy recipes. authorizations is by password
+// update function to edit a recipe where only owners of recipes can edit title, is_community and description. Non owners can only edit descriptions of communtiy recipes. authorization is by the caller's principal
 #[ic_cdk::update]
 fn edit_owned_recipe(payload: EditRecipePayload) -> Result<Recipe, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let caller = caller_user()?;
     let recipe = RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&payload.recipe_id));
 
     match recipe {
         Some(recipe) => {
             if recipe.is_community {
-                return Err(Error::InvalidPayload { msg: format!("You can only change descriptions of community Recipes. Try edit_community_recipe method") });
+                return Err(Error::InvalidPayload { msg: "You can only change descriptions of community Recipes. Try edit_community_recipe method".to_string() });
             }
-            // get recipe writer, by user_id
-            let user = USER_STORAGE.with(|recipes| recipes.borrow().get(&recipe.user_id));
-            match user {
-                Some(user) => {
-                    if user.password == payload.password {
-                        let price = if payload.is_community {
-                            0
-                        } else {
-                            payload.price
-                        };
-                        let new_recipe = Recipe {
-                            id: recipe.id,
-                            title: payload.title.clone(),
-                            description: payload.description,
-                            category: recipe.category,
-                            is_community: payload.is_community,
-                            is_for_sale: payload.is_for_sale,
-                            price,
-                            user_id: recipe.user_id,
-                            reviews: recipe.reviews,
-                        };
-
-                        match RECIPE_STORAGE
-                            .with(|s| s.borrow_mut().insert(recipe.id, new_recipe.clone()))
-                        {
-                            Some(_) => Ok(new_recipe),
-                            None => Err(Error::InvalidPayload {
-                                msg: format!("Could not edit recipe title: {}", payload.title),
-                            }),
-                        }
-                    } else {
-                        return Err(Error::Unauthorized {
-                            msg: format!("Unathorized, only recipe owner can edit this recipe"),
-                        });
-                    }
-                }
-                None => {
-                    return Err(Error::NotFound {
-                        msg: format!("Recipe owner id: {} could not be found", recipe.user_id),
-                    })
+            if caller.id != recipe.user_id {
+                return Err(Error::Unauthorized {
+                    msg: "Unathorized, only recipe owner can edit this recipe".to_string(),
+                });
+            }
+
+            // publishing a copy would hand everyone the key of the whole edition
+            if payload.is_community && recipe.edition.is_some() {
+                return Err(Error::InvalidPayload {
+                    msg: "Copies of a limited edition can not become community recipes".to_string(),
+                });
+            }
+            if payload.is_community && license::license_of(&recipe) == LicenseTier::Personal {
+                return Err(Error::Unauthorized {
+                    msg: "A personal license does not include the right to publish this recipe"
+                        .to_string(),
+                });
+            }
+
+            if payload.is_community && auction::is_auctioned(recipe.id) {
+                return Err(Error::InvalidPayload {
+                    msg: "This recipe is being auctioned".to_string(),
+                });
+            }
+
+            // community recipes can not be sold, close any listing
+            if payload.is_community {
+                listing::close(recipe.id, ListingStatus::Delisted);
+            }
+            let (is_for_sale, price) = if payload.is_community {
+                (false, 0)
+            } else {
+                (recipe.is_for_sale, recipe.price)
+            };
+            let nutrition =
+                nutrition::after_edit(&recipe, payload.body.as_ref(), payload.servings)?;
+            update_body(&recipe, payload.body)?;
+            let new_recipe = Recipe {
+                id: recipe.id,
+                title: payload.title.clone(),
+                description: payload.description,
+                category: recipe.category,
+                is_community: payload.is_community,
+                is_for_sale,
+                price,
+                user_id: recipe.user_id,
+                reviews: recipe.reviews,
+                creator_id: recipe.creator_id,
+                royalty_bps: recipe.royalty_bps,
+                edition: recipe.edition,
+                license: recipe.license,
+                rental_price_per_day: recipe.rental_price_per_day,
+                teaser: payload.teaser.or(recipe.teaser),
+                image_url: payload.image_url.or(recipe.image_url),
+                servings: payload.servings.or(recipe.servings),
+                prep_minutes: payload.prep_minutes.or(recipe.prep_minutes),
+                cook_minutes: payload.cook_minutes.or(recipe.cook_minutes),
+                nutrition,
+                media: recipe.media,
+            };
+            check_size(&new_recipe, "recipe")?;
+
+            match RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, new_recipe.clone())) {
+                Some(_) => {
+                    pages::certify_recipe(recipe.id);
+                    Ok(new_recipe)
                 }
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not edit recipe title: {}", payload.title),
+                }),
             }
         }
         None => Err(Error::NotFound {
@@ -490,16 +1447,22 @@ fn edit_owned_recipe(payload: EditRecipePayload) -> Result<Recipe, Error> {
     }
 }
 
-// define update function to edit community recipes
+// define update function to edit community recipes, any registered user can contribute
 #[ic_cdk::update]
 fn edit_community_recipe(payload: EditCommunityRecipe) -> Result<Recipe, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    caller_user()?;
     let recipe = RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&payload.recipe_id));
 
     match recipe {
         Some(recipe) => {
             if !recipe.is_community {
-                return Err(Error::Unauthorized { msg: format!("This is a private recipe, please contribute to a community based recipe of use the edit_owned_recipe method") });
+                return Err(Error::Unauthorized { msg: "This is a private recipe, please contribute to a community based recipe of use the edit_owned_recipe method".to_string() });
             }
+            let nutrition = nutrition::after_edit(&recipe, payload.body.as_ref(), None)?;
+            update_body(&recipe, payload.body)?;
 
             let new_recipe = Recipe {
                 id: recipe.id,
@@ -511,13 +1474,29 @@ fn edit_community_recipe(payload: EditCommunityRecipe) -> Result<Recipe, Error>
                 price: recipe.price,
                 user_id: recipe.user_id,
                 reviews: recipe.reviews,
+                creator_id: recipe.creator_id,
+                royalty_bps: recipe.royalty_bps,
+                edition: recipe.edition,
+                license: recipe.license,
+                rental_price_per_day: recipe.rental_price_per_day,
+                teaser: recipe.teaser,
+                image_url: recipe.image_url,
+                servings: recipe.servings,
+                prep_minutes: recipe.prep_minutes,
+                cook_minutes: recipe.cook_minutes,
+                nutrition,
+                media: recipe.media,
             };
+            check_size(&new_recipe, "recipe")?;
 
             match RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, new_recipe.clone())) {
                 None => Err(Error::InvalidPayload {
                     msg: format!("Could not edit recipe title: {}", recipe.title),
                 }),
-                Some(_) => Ok(new_recipe),
+                Some(_) => {
+                    pages::certify_recipe(recipe.id);
+                    Ok(new_recipe)
+                }
             }
         }
         None => Err(Error::NotFound {
@@ -526,91 +1505,140 @@ fn edit_community_recipe(payload: EditCommunityRecipe) -> Result<Recipe, Error>
     }
 }
 
-// function to buy recipe NFT
+// replace the body of a recipe when a new one is given
+fn update_body(recipe: &Recipe, body: Option<RecipeBody>) -> Result<(), Error> {
+    let Some(body) = body else {
+        return Ok(());
+    };
+    if recipe.edition.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "Copies of an edition share the body of their edition".to_string(),
+        });
+    }
+    content::store_body(recipe.id, body)
+}
+
+// function to buy recipe NFT, the calling user is the buyer. Any shortfall in
+// the buyer's marketplace balance is pulled from their ledger account first.
+// The bought recipe is returned with its key wrapped for the buyer
 #[ic_cdk::update]
-fn buy_recipe_nft(payload: BuyNftPayload) -> Result<String, Error> {
-    // get recipe
-    let recipe = RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&payload.recipe_id));
+async fn buy_recipe_nft(payload: BuyNftPayload) -> Result<RecipeContent, Error> {
     // get user
-    let user = USER_STORAGE.with(|users| users.borrow().get(&payload.user_id));
+    let user = caller_user()?;
+    if user.encryption_key.is_none() {
+        return Err(Error::InvalidPayload {
+            msg: "Set an encryption key before buying recipes".to_string(),
+        });
+    }
+    let recipe = check_recipe_purchase(&user, payload.recipe_id, payload.tier)?;
+    settlement::check_room(&user)?;
+    let ephemeral = encryption::random_bytes().await?;
+
+    if user.balance < recipe.price {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = recipe.price - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the purchase, the recipe or balances may have changed during the ledger call
+    let user = caller_user()?;
+    let recipe = check_recipe_purchase(&user, payload.recipe_id, payload.tier)?;
+    // check if user has enough balance
+    if user.balance < recipe.price {
+        return Err(Error::InvalidPayload {
+            msg: "You do not have enough balance to buy this recipe".to_string(),
+        });
+    }
+
+    transfer_recipe_to_user(user.id, recipe, payload.tier)?;
+    let recipe = RECIPE_STORAGE
+        .with(|recipes| recipes.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    let public_key = user.encryption_key.unwrap_or_default();
+    content::unlocked_content(recipe, &public_key, ephemeral)
+}
+
+// set the X25519 public key the caller's recipe keys are wrapped for
+#[ic_cdk::update]
+fn set_encryption_key(public_key: Vec<u8>) -> Result<ReturnUser, Error> {
+    encryption::check_public_key(&public_key)?;
+    let user = caller_user()?;
+    let new_user = User {
+        encryption_key: Some(public_key),
+        ..user
+    };
+    check_size(&new_user, "user")?;
+    USER_STORAGE.with(|s| s.borrow_mut().insert(new_user.id, new_user.clone()));
+    Ok(new_user.into())
+}
+
+// check that a user may buy a license on a recipe, returning the recipe priced
+// at that license
+fn check_recipe_purchase(user: &User, recipe_id: u64, tier: LicenseTier) -> Result<Recipe, Error> {
+    // get recipe
+    let recipe = RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&recipe_id));
     match recipe {
         Some(recipe) => {
             // check if recipe is community
             if recipe.is_community {
                 return Err(Error::InvalidPayload {
-                    msg: format!("This is a community recipe, you can not buy it"),
+                    msg: "This is a community recipe, you can not buy it".to_string(),
                 });
             }
 
-            // check if recipe is up for sale
-            if !recipe.is_for_sale {
+            // check if recipe is up for sale, and in the marketplace's current ledger
+            let listing = match listing::active_listing(recipe.id) {
+                Some(listing) => listing,
+                None => {
+                    return Err(Error::InvalidPayload {
+                        msg: "Sorry, This recipe is not currently for sale".to_string(),
+                    })
+                }
+            };
+            if listing.currency != ledger::ledger_canister().ok() {
                 return Err(Error::InvalidPayload {
-                    msg: format!("Sorry, This recipe is not currently for sale"),
+                    msg: "This listing is priced in a ledger the marketplace no longer uses"
+                        .to_string(),
                 });
             }
-            // check if user exists
-            match user {
-                Some(user) => {
-                    // check if the password provided matches users
-                    if user.password != payload.password {
-                        return Err(Error::Unauthorized {
-                            msg: format!("Unauthorized, password does not match, try again"),
-                        });
-                    }
-
-                    // check if user has enough balance
-                    if user.balance < recipe.price {
-                        return Err(Error::InvalidPayload {
-                            msg: format!("You do not have enough balance to buy this recipe"),
-                        });
-                    }
-                    // check if user is not recipe owner
-                    if user.id == recipe.user_id {
-                        return Err(Error::InvalidPayload {
-                            msg: format!("You can not buy your own recipe"),
-                        });
-                    }
-                    // check if user has already bought recipe
-                    if user.recipes.contains(&recipe.id) {
+            // check if user is not recipe owner
+            if user.id == recipe.user_id {
+                return Err(Error::InvalidPayload {
+                    msg: "You can not buy your own recipe".to_string(),
+                });
+            }
+            // check if user has already bought recipe
+            if user.recipes.contains(&recipe.id) {
+                return Err(Error::InvalidPayload {
+                    msg: "You have already bought this recipe".to_string(),
+                });
+            }
+            let price = match tier {
+                LicenseTier::Personal => listing.price,
+                LicenseTier::Commercial => match listing.commercial_price {
+                    Some(price) => price,
+                    None => {
                         return Err(Error::InvalidPayload {
-                            msg: format!("You have already bought this recipe"),
-                        });
-                    }
-                    // get recipe owner
-                    match transfer_recipe_to_user(payload.user_id, recipe.clone()) {
-                        Ok(_) => (),
-                        Err(e) => return Err(e),
-                    }
-
-                    match RECIPE_STORAGE.with(|r| {
-                        r.borrow_mut().insert(
-                            recipe.id,
-                            Recipe {
-                                user_id: user.id,
-                                ..recipe
-                            },
-                        )
-                    }) {
-                        Some(_) => Ok(format!("Recipe bought successfully, Enjoy !!")),
-                        None => Err(Error::NotFound {
-                            msg: "could not update recipe user_id".to_string(),
-                        }),
+                            msg: "A commercial license is not offered for this recipe".to_string(),
+                        })
                     }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("user not found"),
-                }),
-            }
+                },
+            };
+            Ok(Recipe { price, ..recipe })
         }
         None => Err(Error::NotFound {
-            msg: format!("recipe not found"),
+            msg: "recipe not found".to_string(),
         }),
     }
 }
 
 // add review to recipe
 #[ic_cdk::update]
-fn add_review(payload: ReviewPayload) -> Result<Recipe, Error> {
+fn add_review(payload: ReviewPayload) -> Result<RecipePreview, Error> {
+    caller_user()?;
     // get recipe
     let recipe = RECIPE_STORAGE.with(|recipes| recipes.borrow().get(&payload.recipe_id));
     match recipe {
@@ -621,9 +1649,13 @@ fn add_review(payload: ReviewPayload) -> Result<Recipe, Error> {
                 reviews: new_reviews,
                 ..recipe
             };
+            check_size(&new_recipe, "recipe with this review")?;
 
             match RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, new_recipe.clone())) {
-                Some(_) => Ok(new_recipe),
+                Some(_) => {
+                    pages::certify_recipe(recipe.id);
+                    Ok(new_recipe.into())
+                }
                 None => Err(Error::InvalidPayload {
                     msg: format!("Could not add review to recipe id: {}", recipe.id),
                 }),
@@ -648,90 +1680,48 @@ fn get_recipe_reviews(id: u64) -> Result<Vec<String>, Error> {
     }
 }
 
-fn transfer_recipe_to_user(user_id: u64, recipe: Recipe) -> Result<(), Error> {
-    let recipe_owner = USER_STORAGE.with(|users| users.borrow().get(&recipe.user_id));
-    let recipe_buyer = USER_STORAGE.with(|users| users.borrow().get(&user_id));
-
-    match recipe_owner {
-        Some(recipe_owner) => {
-            let mut new_user_recipes = recipe_owner.recipes.clone();
-            new_user_recipes = new_user_recipes
-                .into_iter()
-                .filter(|r| *r != recipe.id)
-                .collect();
-            // update recipe owner balance and remove bought recipe
-            let new_recipe_owner = User {
-                recipes: new_user_recipes,
-                balance: recipe_owner.balance + recipe.price,
-                ..recipe_owner
-            };
-            // update recipe owner in storage
-            match USER_STORAGE.with(|s| {
-                s.borrow_mut()
-                    .insert(recipe_owner.id, new_recipe_owner.clone())
-            }) {
-                Some(_) => {
-                    // update user balance
-                    match recipe_buyer {
-                        Some(recipe_buyer) => {
-                            let mut new_user_recipes = recipe_buyer.recipes.clone();
-                            new_user_recipes.push(recipe.id);
-                            let new_user = User {
-                                recipes: new_user_recipes,
-                                balance: recipe_buyer.balance - recipe.price.clone(),
-                                ..recipe_buyer
-                            };
-                            // update user balance in storage
-                            match USER_STORAGE
-                                .with(|s| s.borrow_mut().insert(recipe_buyer.id, new_user.clone()))
-                            {
-                                Some(_) => Ok(()),
-                                None => Err(Error::InvalidPayload {
-                                    msg: format!("Could not update user balance"),
-                                }),
-                            }
-                        }
-                        None => Err(Error::NotFound {
-                            msg: format!("Could not find recipe Buyer"),
-                        }),
-                    }
-                }
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not update recipe owner balance"),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("Could not find recipe owner"),
-        }),
-    }
+// settle the sale of a recipe to a user at its listed price, paying the seller
+// from the buyer's balance and moving ownership with the given license in one
+// unit. Returns the block index of the sale
+fn transfer_recipe_to_user(
+    user_id: u64,
+    recipe: Recipe,
+    license: LicenseTier,
+) -> Result<u64, Error> {
+    let price = recipe.price;
+    Settlement::purchase(recipe, user_id, price)
+        .with_license(license)
+        .apply()
 }
 
 // Define query function to get a user by ID
 #[ic_cdk::query]
 fn get_user(id: u64) -> Result<ReturnUser, Error> {
     match USER_STORAGE.with(|users| users.borrow().get(&id)) {
-        Some(user) => Ok(ReturnUser {
-            id: user.id,
-            name: user.name,
-            email: user.email,
-            recipes: user.recipes,
-            balance: user.balance,
-        }),
+        Some(user) => Ok(user.into()),
         None => Err(Error::NotFound {
             msg: format!("user id:{} does not exist", id),
         }),
     }
 }
 
-// Update function to add a user
+// Update function to add a user bound to the caller's principal
 #[ic_cdk::update]
-fn add_user(payload: UserPayload) -> Result<ReturnUser, Error> {
+async fn add_user(payload: UserPayload) -> Result<ReturnUser, Error> {
     // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if let Some(public_key) = &payload.encryption_key {
+        encryption::check_public_key(public_key)?;
+    }
+    let principal = authenticated_caller()?;
+    let salt = password::new_salt().await?;
+
+    // a principal can only be registered once
+    if caller_user().is_ok() {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!("A user is already registered for principal: {}", principal),
         });
     }
 
@@ -744,26 +1734,206 @@ fn add_user(payload: UserPayload) -> Result<ReturnUser, Error> {
 
     let user = User {
         id,
+        principal,
         name: payload.name.clone(),
         email: payload.email,
-        password: payload.password,
+        password: PasswordHash::new(&payload.password, salt),
         recipes: vec![],
         balance: 0,
+        encryption_key: payload.encryption_key,
     };
-
-    let return_user = ReturnUser {
-        id,
-        name: user.name.clone(),
-        email: user.email.clone(),
-        recipes: user.recipes.clone(),
-        balance: user.balance.clone(),
-    };
+    check_size(&user, "user")?;
 
     match USER_STORAGE.with(|s| s.borrow_mut().insert(id, user.clone())) {
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add user name: {}", payload.name),
         }),
-        None => Ok(return_user),
+        None => {
+            index_principal(&user);
+            pages::certify_user(id);
+            Ok(user.into())
+        }
+    }
+}
+
+// Update function for the calling user to change their password
+#[ic_cdk::update]
+async fn change_password(payload: ChangePasswordPayload) -> Result<String, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let user = caller_user()?;
+    if !user.password.verify(&payload.old_password) {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, password does not match, try again".to_string(),
+        });
+    }
+    let salt = password::new_salt().await?;
+
+    set_user_password(user.id, &payload.new_password, salt)?;
+    Ok("Password changed successfully".to_string())
+}
+
+// Update function for the contract owner to reset a user's password
+#[ic_cdk::update]
+async fn reset_user_password(payload: ResetPasswordPayload) -> Result<String, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    caller_contract_owner()?;
+    let salt = password::new_salt().await?;
+
+    set_user_password(payload.user_id, &payload.new_password, salt)?;
+    Ok(format!("Password reset for user id: {}", payload.user_id))
+}
+
+// Update function to bind a user created before principal authentication to the caller
+#[ic_cdk::update]
+fn claim_user(payload: ClaimUserPayload) -> Result<ReturnUser, Error> {
+    let principal = authenticated_caller()?;
+    if caller_user().is_ok() {
+        return Err(Error::InvalidPayload {
+            msg: format!("A user is already registered for principal: {}", principal),
+        });
+    }
+
+    match USER_STORAGE.with(|users| users.borrow().get(&payload.user_id)) {
+        Some(user) => {
+            if user.principal != Principal::anonymous() {
+                return Err(Error::Unauthorized {
+                    msg: format!("user id:{} has already been claimed", user.id),
+                });
+            }
+            if !user.password.verify(&payload.password) {
+                return Err(Error::Unauthorized {
+                    msg: "Unauthorized, password does not match, try again".to_string(),
+                });
+            }
+            let new_user = User { principal, ..user };
+            check_size(&new_user, "user")?;
+            USER_STORAGE.with(|s| s.borrow_mut().insert(new_user.id, new_user.clone()));
+            index_principal(&new_user);
+            Ok(new_user.into())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("user id:{} does not exist", payload.user_id),
+        }),
+    }
+}
+
+// store a freshly salted hash of a user's new password
+fn set_user_password(user_id: u64, new_password: &str, salt: Vec<u8>) -> Result<(), Error> {
+    match USER_STORAGE.with(|users| users.borrow().get(&user_id)) {
+        Some(user) => {
+            let new_user = User {
+                password: PasswordHash::new(new_password, salt),
+                ..user
+            };
+            check_size(&new_user, "user")?;
+            USER_STORAGE.with(|s| s.borrow_mut().insert(user_id, new_user));
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("user id:{} does not exist", user_id),
+        }),
+    }
+}
+
+// Rehash any plaintext passwords left in stable memory by earlier versions
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // before anything reads recipes or editions
+    migrate_bounded_storage();
+    index_user_principals();
+    // raw_rand can't be awaited during the upgrade itself, so migrate right after it
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(migrate_legacy_passwords()));
+    // expires overdue listings, so migrated recipes are not listed again
+    listing::reschedule_active_listings();
+    listing::migrate_legacy_listings();
+    auction::reschedule_open_auctions();
+    dutch_auction::reschedule_open_auctions();
+    offer::reschedule_pending_offers();
+    rental::reschedule_active_rentals();
+    subscription::reschedule_billing();
+    media::reschedule_pending_uploads();
+    media::certify_all();
+    pages::certify_all();
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(content::migrate_legacy_bodies())
+    });
+}
+
+// index the principals of users registered before the index existed
+fn index_user_principals() {
+    if !USER_BY_PRINCIPAL.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let users: Vec<User> = USER_STORAGE.with(|s| s.borrow().iter().map(|(_, user)| user).collect());
+    for user in &users {
+        index_principal(user);
+    }
+}
+
+// copy users and recipes out of the maps bounded to 1024 bytes, emptying
+// them so later upgrades have nothing left to copy
+fn migrate_bounded_storage() {
+    let users: Vec<(u64, User)> =
+        LEGACY_USER_STORAGE.with(|s| s.borrow().iter().map(|(id, user)| (id, user.0)).collect());
+    for (id, user) in users {
+        USER_STORAGE.with(|s| s.borrow_mut().insert(id, user));
+        LEGACY_USER_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    }
+    let recipes: Vec<(u64, Recipe)> = LEGACY_RECIPE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(id, recipe)| (id, recipe.0))
+            .collect()
+    });
+    for (id, recipe) in recipes {
+        RECIPE_STORAGE.with(|s| s.borrow_mut().insert(id, recipe));
+        LEGACY_RECIPE_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    }
+}
+
+async fn migrate_legacy_passwords() {
+    let seed = match password::new_salt().await {
+        Ok(seed) => seed,
+        Err(_) => {
+            ic_cdk::println!("Could not get randomness, retrying password migration");
+            ic_cdk_timers::set_timer(Duration::from_secs(60), || {
+                ic_cdk::spawn(migrate_legacy_passwords())
+            });
+            return;
+        }
+    };
+
+    let legacy_users: Vec<User> = USER_STORAGE.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .map(|(_, user)| user)
+            .filter(|user| user.password.is_legacy())
+            .collect()
+    });
+    for user in legacy_users {
+        let salt = password::derive_salt(&seed, user.id);
+        let new_user = User {
+            password: user.password.migrate(salt),
+            ..user
+        };
+        USER_STORAGE.with(|s| s.borrow_mut().insert(new_user.id, new_user));
+    }
+
+    if let Some(contract) = CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
+        if contract.password.is_legacy() {
+            // contract and user ids share the seed, so salt the contract from a distinct id
+            let salt = password::derive_salt(&seed, u64::MAX);
+            let new_contract = Contract {
+                password: contract.password.migrate(salt),
+                ..contract
+            };
+            CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, new_contract));
+        }
     }
 }
 
@@ -774,6 +1944,10 @@ enum Error {
     AlreadyInit { msg: String },
     InvalidPayload { msg: String },
     Unauthorized { msg: String },
+    AnonymousCaller { msg: String },
+    CallFailed { msg: String },
+    PaymentFailed { msg: String },
+    SettlementFailed { msg: String },
 }
 
 // Candid generator for exporting the Candid interface
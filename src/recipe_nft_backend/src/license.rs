@@ -0,0 +1,33 @@
+use crate::{Error, Recipe, RECIPE_STORAGE};
+
+// The rights that come with owning a recipe. A personal license is for home
+// cooking only, a commercial license also covers selling dishes made from it
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub(crate) enum LicenseTier {
+    #[default]
+    Personal,
+    Commercial,
+}
+
+// the license held by the owner of a recipe. Creators, and owners who bought
+// before license tiers existed, hold commercial rights
+pub(crate) fn license_of(recipe: &Recipe) -> LicenseTier {
+    recipe.license.unwrap_or(LicenseTier::Commercial)
+}
+
+// get the license a user holds on a recipe, for apps enforcing it
+#[ic_cdk::query]
+fn get_license(user_id: u64, recipe_id: u64) -> Result<LicenseTier, Error> {
+    match RECIPE_STORAGE.with(|s| s.borrow().get(&recipe_id)) {
+        Some(recipe) if recipe.user_id == user_id => Ok(license_of(&recipe)),
+        Some(_) => Err(Error::NotFound {
+            msg: format!(
+                "user id:{} holds no license on recipe id: {}",
+                user_id, recipe_id
+            ),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        }),
+    }
+}
@@ -0,0 +1,367 @@
+use crate::history::{self, TransactionKind};
+use crate::license::{license_of, LicenseTier};
+use crate::pages;
+use crate::{
+    auction, caller_user, ledger, marketplace_config, Error, Recipe, ACTIVE_LISTING_STORAGE,
+    ID_COUNTER, LISTINGS_MIGRATED, LISTING_STORAGE, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+
+// listings can run for at most 90 days, and for 30 when no expiry is given
+const MAX_LISTING_DURATION: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+pub(crate) const DEFAULT_LISTING_DURATION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ListingStatus {
+    Active,
+    Sold,
+    Delisted,
+    Expired,
+}
+
+// An offer to sell a recipe at a fixed price until 'expires_at'. 'currency' is
+// the ledger canister the price is denominated in, None when the marketplace
+// had no ledger when the recipe was listed. A recipe has at most one active
+// listing, and its 'is_for_sale' and 'price' fields mirror it.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Listing {
+    id: u64,
+    pub(crate) recipe_id: u64,
+    seller_id: u64,
+    // price of a personal license
+    pub(crate) price: u64,
+    // price of a commercial license, None when only personal licenses are sold
+    pub(crate) commercial_price: Option<u64>,
+    pub(crate) currency: Option<Principal>,
+    created_at: u64,
+    expires_at: u64,
+    status: ListingStatus,
+}
+
+impl Storable for Listing {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Listing {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ListingPayload {
+    recipe_id: u64,
+    price: u64,
+    commercial_price: Option<u64>,
+    // nanoseconds since the epoch, defaults to 30 days from now
+    expires_at: Option<u64>,
+}
+
+// store a listing, keeping the index of active listings by recipe in line
+fn store(listing: &Listing) {
+    LISTING_STORAGE.with(|s| s.borrow_mut().insert(listing.id, listing.clone()));
+    ACTIVE_LISTING_STORAGE.with(|s| {
+        let mut index = s.borrow_mut();
+        if listing.status == ListingStatus::Active {
+            index.insert(listing.recipe_id, listing.id);
+        } else if index.get(&listing.recipe_id) == Some(listing.id) {
+            index.remove(&listing.recipe_id);
+        }
+    });
+}
+
+// a listing that is still active, None once it was closed or ran out
+fn if_active(listing_id: u64, now: u64) -> Option<Listing> {
+    LISTING_STORAGE
+        .with(|s| s.borrow().get(&listing_id))
+        .filter(|l| l.status == ListingStatus::Active && now < l.expires_at)
+}
+
+// the listing a recipe is currently for sale under
+pub(crate) fn active_listing(recipe_id: u64) -> Option<Listing> {
+    let listing_id = ACTIVE_LISTING_STORAGE.with(|s| s.borrow().get(&recipe_id))?;
+    if_active(listing_id, ic_cdk::api::time())
+}
+
+// keep the recipe's sale fields in line with its listing
+fn mirror(recipe_id: u64, price: Option<u64>) {
+    if let Some(recipe) = RECIPE_STORAGE.with(|s| s.borrow().get(&recipe_id)) {
+        let new_recipe = Recipe {
+            is_for_sale: price.is_some(),
+            price: price.unwrap_or(recipe.price),
+            ..recipe
+        };
+        RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe_id, new_recipe));
+        pages::certify_recipe(recipe_id);
+    }
+}
+
+// check the prices and expiry of a new or updated listing, returning the
+// expiry. Only owners with commercial rights can sell commercial licenses
+fn check_terms(recipe: &Recipe, payload: &ListingPayload) -> Result<u64, Error> {
+    let config = marketplace_config();
+    config.check_price(payload.price)?;
+    if let Some(commercial_price) = payload.commercial_price {
+        if license_of(recipe) != LicenseTier::Commercial {
+            return Err(Error::Unauthorized {
+                msg: "Only owners with a commercial license can sell one".to_string(),
+            });
+        }
+        config.check_price(commercial_price)?;
+    }
+    let now = ic_cdk::api::time();
+    let expires_at = payload.expires_at.unwrap_or(now + DEFAULT_LISTING_DURATION);
+    if expires_at <= now || expires_at - now > MAX_LISTING_DURATION {
+        return Err(Error::InvalidPayload {
+            msg: "Listings must expire in the future and within 90 days".to_string(),
+        });
+    }
+    Ok(expires_at)
+}
+
+// put a recipe up for sale, logging the listing. Callers check ownership and
+// that the recipe is not already listed or auctioned
+pub(crate) fn create(
+    recipe: &Recipe,
+    price: u64,
+    commercial_price: Option<u64>,
+    expires_at: u64,
+) -> Listing {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let listing = Listing {
+        id,
+        recipe_id: recipe.id,
+        seller_id: recipe.user_id,
+        price,
+        commercial_price,
+        currency: ledger::ledger_canister().ok(),
+        created_at: ic_cdk::api::time(),
+        expires_at,
+        status: ListingStatus::Active,
+    };
+    store(&listing);
+    mirror(recipe.id, Some(price));
+    record(&listing);
+    schedule_expiry(&listing);
+    listing
+}
+
+// close the recipe's active listing, if it has one
+pub(crate) fn close(recipe_id: u64, status: ListingStatus) {
+    if let Some(listing) = active_listing(recipe_id) {
+        store(&Listing { status, ..listing });
+    }
+    mirror(recipe_id, None);
+}
+
+// log that a recipe was put up for sale at a price
+fn record(listing: &Listing) {
+    history::record(
+        TransactionKind::List,
+        Some(listing.recipe_id),
+        Some(listing.seller_id),
+        None,
+        Some(listing.price),
+    );
+}
+
+// expire the listing once its deadline has passed
+fn schedule_expiry(listing: &Listing) {
+    let delay = listing.expires_at.saturating_sub(ic_cdk::api::time());
+    let listing_id = listing.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || expire(listing_id));
+}
+
+fn expire(listing_id: u64) {
+    let listing = match LISTING_STORAGE.with(|s| s.borrow().get(&listing_id)) {
+        Some(listing) if listing.status == ListingStatus::Active => listing,
+        _ => return,
+    };
+    // the listing may have been extended since this timer was set
+    if ic_cdk::api::time() < listing.expires_at {
+        return;
+    }
+    store(&Listing {
+        status: ListingStatus::Expired,
+        ..listing.clone()
+    });
+    mirror(listing.recipe_id, None);
+}
+
+// timers do not survive upgrades: expire the listings that ran out in the
+// meantime and reschedule expiry of the others
+pub(crate) fn reschedule_active_listings() {
+    let active: Vec<Listing> = LISTING_STORAGE.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .map(|(_, l)| l)
+            .filter(|l| l.status == ListingStatus::Active)
+            .collect()
+    });
+    let now = ic_cdk::api::time();
+    for listing in active {
+        if listing.expires_at <= now {
+            expire(listing.id);
+        } else {
+            schedule_expiry(&listing);
+        }
+    }
+}
+
+// recipes marked for sale before listings existed get a listing at their
+// price, and listings created before the index existed are indexed, once
+pub(crate) fn migrate_legacy_listings() {
+    if LISTINGS_MIGRATED.with(|flag| *flag.borrow().get()) != 0 {
+        return;
+    }
+    let active: Vec<Listing> = LISTING_STORAGE.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .map(|(_, l)| l)
+            .filter(|l| l.status == ListingStatus::Active)
+            .collect()
+    });
+    for listing in active {
+        store(&listing);
+    }
+    let listed: Vec<Recipe> = RECIPE_STORAGE.with(|recipes| {
+        recipes
+            .borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.is_for_sale && !r.is_community)
+            .collect()
+    });
+    let expires_at = ic_cdk::api::time() + DEFAULT_LISTING_DURATION;
+    for recipe in listed {
+        if active_listing(recipe.id).is_none() {
+            create(&recipe, recipe.price, None, expires_at);
+        }
+    }
+    LISTINGS_MIGRATED
+        .with(|flag| flag.borrow_mut().set(1))
+        .expect("Cannot record the listing migration");
+}
+
+// the caller's recipe, if it can be listed
+fn listable_recipe(recipe_id: u64) -> Result<Recipe, Error> {
+    let user = caller_user()?;
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        })?;
+    if recipe.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the recipe owner can list this recipe".to_string(),
+        });
+    }
+    if recipe.is_community {
+        return Err(Error::InvalidPayload {
+            msg: "This is a community recipe, you can not sell it".to_string(),
+        });
+    }
+    Ok(recipe)
+}
+
+// put one of the caller's recipes up for sale at a fixed price
+#[ic_cdk::update]
+fn list_recipe(payload: ListingPayload) -> Result<Listing, Error> {
+    let recipe = listable_recipe(payload.recipe_id)?;
+    if active_listing(recipe.id).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "This recipe is already listed, use update_listing".to_string(),
+        });
+    }
+    if auction::is_auctioned(recipe.id) {
+        return Err(Error::InvalidPayload {
+            msg: "This recipe is being auctioned".to_string(),
+        });
+    }
+    let expires_at = check_terms(&recipe, &payload)?;
+    Ok(create(
+        &recipe,
+        payload.price,
+        payload.commercial_price,
+        expires_at,
+    ))
+}
+
+// change the price or expiry of the caller's active listing
+#[ic_cdk::update]
+fn update_listing(payload: ListingPayload) -> Result<Listing, Error> {
+    let recipe = listable_recipe(payload.recipe_id)?;
+    let listing = active_listing(recipe.id).ok_or_else(|| Error::NotFound {
+        msg: format!("recipe of id: {} is not listed", recipe.id),
+    })?;
+    let expires_at = check_terms(&recipe, &payload)?;
+    let new_listing = Listing {
+        price: payload.price,
+        commercial_price: payload.commercial_price,
+        expires_at,
+        ..listing.clone()
+    };
+    store(&new_listing);
+    mirror(recipe.id, Some(new_listing.price));
+    if new_listing.price != listing.price {
+        record(&new_listing);
+    }
+    if new_listing.expires_at != listing.expires_at {
+        schedule_expiry(&new_listing);
+    }
+    Ok(new_listing)
+}
+
+// take the caller's recipe off sale
+#[ic_cdk::update]
+fn delist_recipe(recipe_id: u64) -> Result<Recipe, Error> {
+    let recipe = listable_recipe(recipe_id)?;
+    if active_listing(recipe.id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("recipe of id: {} is not listed", recipe.id),
+        });
+    }
+    close(recipe.id, ListingStatus::Delisted);
+    Ok(Recipe {
+        is_for_sale: false,
+        ..recipe
+    })
+}
+
+#[ic_cdk::query]
+fn get_listing(recipe_id: u64) -> Result<Listing, Error> {
+    active_listing(recipe_id).ok_or_else(|| Error::NotFound {
+        msg: format!("recipe of id: {} is not listed", recipe_id),
+    })
+}
+
+// get every active listing
+#[ic_cdk::query]
+fn get_active_listings() -> Vec<Listing> {
+    active_listings()
+}
+
+pub(crate) fn active_listings() -> Vec<Listing> {
+    let now = ic_cdk::api::time();
+    let listing_ids: Vec<u64> =
+        ACTIVE_LISTING_STORAGE.with(|index| index.borrow().iter().map(|(_, id)| id).collect());
+    listing_ids
+        .into_iter()
+        .filter_map(|id| if_active(id, now))
+        .collect()
+}
@@ -0,0 +1,527 @@
+use crate::http::{self, HttpResponse, StreamingCallbackHttpResponse, StreamingToken};
+use crate::pages;
+use crate::{
+    caller_user, check_size, Error, Recipe, FREE_MEDIA_STORAGE, ID_COUNTER, MEDIA_CURSOR,
+    MEDIA_REGION, MEDIA_STORAGE, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Memory, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, time::Duration};
+use validator::Validate;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+// largest chunk accepted in one call, and served in one http response
+pub(crate) const CHUNK_SIZE: u64 = 1024 * 1024;
+const MAX_IMAGE_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_VIDEO_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_MEDIA_PER_RECIPE: usize = 8;
+const MAX_PENDING_UPLOADS: usize = 2;
+// space reserved by unfinished uploads of all users together, so uploads that
+// never send their bytes can not grow the media region without bound
+const MAX_PENDING_BYTES: u64 = 256 * 1024 * 1024;
+// unfinished uploads are dropped an hour after they began
+const UPLOAD_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+// content types media can be uploaded as, with the largest size allowed for each
+const CONTENT_TYPES: [(&str, u64); 6] = [
+    ("image/jpeg", MAX_IMAGE_SIZE),
+    ("image/png", MAX_IMAGE_SIZE),
+    ("image/webp", MAX_IMAGE_SIZE),
+    ("image/gif", MAX_IMAGE_SIZE),
+    ("video/mp4", MAX_VIDEO_SIZE),
+    ("video/webm", MAX_VIDEO_SIZE),
+];
+
+// An image or video attached to a recipe. Its bytes are kept in the media
+// region from 'offset', and are only served once all of them were uploaded
+// and matched 'sha256'
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Media {
+    id: u64,
+    recipe_id: u64,
+    uploader_id: u64,
+    content_type: String,
+    size: u64,
+    sha256: Vec<u8>,
+    offset: u64,
+    // bytes uploaded so far, chunks are written in order
+    received: u64,
+    is_complete: bool,
+    created_at: u64,
+}
+
+impl Storable for Media {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Media {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// How a recipe refers to one of its media
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MediaRef {
+    pub(crate) id: u64,
+    pub(crate) content_type: String,
+    pub(crate) size: u64,
+    pub(crate) url: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct MediaUploadPayload {
+    recipe_id: u64,
+    #[validate(length(min = 1, max = 50))]
+    content_type: String,
+    size: u64,
+    // hash of the whole file, checked when the upload is finished
+    #[validate(length(equal = 32))]
+    sha256: Vec<u8>,
+}
+
+fn get_media(media_id: u64) -> Result<Media, Error> {
+    MEDIA_STORAGE
+        .with(|s| s.borrow().get(&media_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("media of id: {} not found", media_id),
+        })
+}
+
+fn store(media: &Media) {
+    MEDIA_STORAGE.with(|s| s.borrow_mut().insert(media.id, media.clone()));
+}
+
+fn is_image(content_type: &str) -> bool {
+    content_type.starts_with("image/")
+}
+
+// the address a media is served at by 'http_request'
+pub(crate) fn url(media_id: u64) -> String {
+    format!("{}{}", http::base_url(), path(media_id))
+}
+
+fn path(media_id: u64) -> String {
+    format!("/media/{}", media_id)
+}
+
+// the path and hash a finished media is certified with
+fn certify(media: &Media) {
+    let mut sha256 = [0u8; 32];
+    sha256.copy_from_slice(&media.sha256);
+    http::certify(&path(media.id), sha256);
+}
+
+// certify every finished media again, certified hashes do not survive upgrades
+pub(crate) fn certify_all() {
+    MEDIA_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, media)| media.is_complete)
+            .for_each(|(_, media)| certify(&media))
+    });
+}
+
+// the finished media of a recipe, in the order they were uploaded
+pub(crate) fn refs(recipe: &Recipe) -> Vec<MediaRef> {
+    recipe
+        .media
+        .iter()
+        .flatten()
+        .filter_map(|id| MEDIA_STORAGE.with(|s| s.borrow().get(id)))
+        .filter(|media| media.is_complete)
+        .map(|media| MediaRef {
+            url: url(media.id),
+            id: media.id,
+            content_type: media.content_type,
+            size: media.size,
+        })
+        .collect()
+}
+
+// the first image of a recipe, shown where a recipe has no image url
+pub(crate) fn cover_url(media: &[MediaRef]) -> Option<String> {
+    media
+        .iter()
+        .find(|media| is_image(&media.content_type))
+        .map(|media| media.url.clone())
+}
+
+// get a recipe the caller owns or created, the only users who can attach media to it
+fn owned_recipe(recipe_id: u64, user_id: u64) -> Result<Recipe, Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        })?;
+    if recipe.user_id != user_id && recipe.creator_id != Some(user_id) {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, only the recipe's owner or creator can manage its media"
+                .to_string(),
+        });
+    }
+    Ok(recipe)
+}
+
+// reserve 'size' bytes of the media region, in the first freed extent large
+// enough or else at its end, growing it as needed
+fn reserve(size: u64) -> Result<u64, Error> {
+    let free = FREE_MEDIA_STORAGE.with(|s| s.borrow().iter().find(|(_, len)| *len >= size));
+    if let Some((offset, len)) = free {
+        FREE_MEDIA_STORAGE.with(|s| {
+            let mut extents = s.borrow_mut();
+            extents.remove(&offset);
+            if len > size {
+                extents.insert(offset + size, len - size);
+            }
+        });
+        return Ok(offset);
+    }
+
+    let offset = MEDIA_CURSOR.with(|cursor| *cursor.borrow().get());
+    let end = offset + size;
+    let pages = MEDIA_REGION.with(|region| region.size());
+    let needed = end.div_ceil(WASM_PAGE_SIZE);
+    if needed > pages && MEDIA_REGION.with(|region| region.grow(needed - pages)) < 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Out of stable memory for media".to_string(),
+        });
+    }
+    MEDIA_CURSOR
+        .with(|cursor| cursor.borrow_mut().set(end))
+        .expect("Cannot move the media cursor");
+    Ok(offset)
+}
+
+// give back the extent of a media, merged with the freed extents around it.
+// An extent at the end of the region moves the end back instead
+fn release(offset: u64, size: u64) {
+    let (mut offset, mut size) = (offset, size);
+    FREE_MEDIA_STORAGE.with(|s| {
+        let mut extents = s.borrow_mut();
+        if let Some(next) = extents.remove(&(offset + size)) {
+            size += next;
+        }
+        if let Some((before, len)) = extents.iter_upper_bound(&offset).next() {
+            if before + len == offset {
+                extents.remove(&before);
+                offset = before;
+                size += len;
+            }
+        }
+    });
+    let end = MEDIA_CURSOR.with(|cursor| *cursor.borrow().get());
+    if offset + size == end {
+        MEDIA_CURSOR
+            .with(|cursor| cursor.borrow_mut().set(offset))
+            .expect("Cannot move the media cursor");
+    } else {
+        FREE_MEDIA_STORAGE.with(|s| s.borrow_mut().insert(offset, size));
+    }
+}
+
+// remove a media and free its bytes
+fn discard(media: &Media) {
+    MEDIA_STORAGE.with(|s| s.borrow_mut().remove(&media.id));
+    release(media.offset, media.size);
+}
+
+fn pending_uploads() -> Vec<Media> {
+    MEDIA_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, media)| media)
+            .filter(|media| !media.is_complete)
+            .collect()
+    })
+}
+
+// drop the upload if it is still unfinished when it times out
+fn schedule_expiry(media: &Media) {
+    let delay = (media.created_at + UPLOAD_TIMEOUT).saturating_sub(ic_cdk::api::time());
+    let media_id = media.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || {
+        if let Ok(media) = get_media(media_id) {
+            if !media.is_complete {
+                discard(&media);
+            }
+        }
+    });
+}
+
+// timers do not survive upgrades, reschedule expiry of every unfinished upload
+pub(crate) fn reschedule_pending_uploads() {
+    for media in pending_uploads() {
+        schedule_expiry(&media);
+    }
+}
+
+fn read(offset: u64, len: u64) -> Vec<u8> {
+    let mut bytes = vec![0; len as usize];
+    MEDIA_REGION.with(|region| region.read(offset, &mut bytes));
+    bytes
+}
+
+// start uploading an image or video for a recipe. The bytes are then sent in
+// order with 'upload_media_chunk' and checked with 'finish_media_upload'
+#[ic_cdk::update]
+fn begin_media_upload(payload: MediaUploadPayload) -> Result<Media, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let user = caller_user()?;
+    let recipe = owned_recipe(payload.recipe_id, user.id)?;
+    let content_type = payload.content_type.to_lowercase();
+    let Some((_, max_size)) = CONTENT_TYPES.iter().find(|(t, _)| *t == content_type) else {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Content type: {} is not supported, use one of: {}",
+                content_type,
+                CONTENT_TYPES.map(|(t, _)| t).join(", ")
+            ),
+        });
+    };
+    if payload.size == 0 || payload.size > *max_size {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "{} files must be between 1 and {} bytes",
+                content_type, max_size
+            ),
+        });
+    }
+    let pending = pending_uploads();
+    if pending.iter().filter(|m| m.uploader_id == user.id).count() >= MAX_PENDING_UPLOADS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Finish or delete your {} unfinished uploads first",
+                MAX_PENDING_UPLOADS
+            ),
+        });
+    }
+    let reserved: u64 = pending.iter().map(|m| m.size).sum();
+    if reserved + payload.size > MAX_PENDING_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: "Too many uploads are in progress, try again later".to_string(),
+        });
+    }
+    // uploads still in progress count towards the recipe's media
+    let attached = recipe.media.as_ref().map_or(0, Vec::len)
+        + pending.iter().filter(|m| m.recipe_id == recipe.id).count();
+    if attached >= MAX_MEDIA_PER_RECIPE {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Recipes can have at most {} images and videos",
+                MAX_MEDIA_PER_RECIPE
+            ),
+        });
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let media = Media {
+        id,
+        recipe_id: recipe.id,
+        uploader_id: user.id,
+        content_type,
+        size: payload.size,
+        sha256: payload.sha256,
+        offset: reserve(payload.size)?,
+        received: 0,
+        is_complete: false,
+        created_at: ic_cdk::api::time(),
+    };
+    store(&media);
+    schedule_expiry(&media);
+    Ok(media)
+}
+
+// upload the next chunk of a media, starting at byte 'offset' of the file
+#[ic_cdk::update]
+fn upload_media_chunk(media_id: u64, offset: u64, chunk: Vec<u8>) -> Result<Media, Error> {
+    let user = caller_user()?;
+    let media = get_media(media_id)?;
+    if media.uploader_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, only the uploader can upload this media".to_string(),
+        });
+    }
+    if media.is_complete {
+        return Err(Error::InvalidPayload {
+            msg: format!("media of id: {} is already uploaded", media_id),
+        });
+    }
+    if offset != media.received {
+        return Err(Error::InvalidPayload {
+            msg: format!("Expected the chunk starting at byte {}", media.received),
+        });
+    }
+    let len = chunk.len() as u64;
+    if len == 0 || len > CHUNK_SIZE || offset + len > media.size {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Chunks must be 1 to {} bytes and end within the {} byte file",
+                CHUNK_SIZE, media.size
+            ),
+        });
+    }
+    MEDIA_REGION.with(|region| region.write(media.offset + offset, &chunk));
+    let media = Media {
+        received: offset + len,
+        ..media
+    };
+    store(&media);
+    Ok(media)
+}
+
+// check an uploaded media against its hash and attach it to its recipe. On a
+// mismatch the upload starts over from the first byte
+#[ic_cdk::update]
+fn finish_media_upload(media_id: u64) -> Result<Media, Error> {
+    let user = caller_user()?;
+    let media = get_media(media_id)?;
+    if media.uploader_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, only the uploader can finish this media".to_string(),
+        });
+    }
+    if media.is_complete {
+        return Ok(media);
+    }
+    if media.received != media.size {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} of {} bytes were uploaded", media.received, media.size),
+        });
+    }
+    let mut hasher = Sha256::new();
+    let mut at = 0;
+    while at < media.size {
+        let len = CHUNK_SIZE.min(media.size - at);
+        hasher.update(read(media.offset + at, len));
+        at += len;
+    }
+    if hasher.finalize().as_slice() != media.sha256.as_slice() {
+        store(&Media {
+            received: 0,
+            ..media
+        });
+        return Err(Error::InvalidPayload {
+            msg: "Uploaded bytes do not match the media's sha256, upload them again".to_string(),
+        });
+    }
+
+    // the recipe may have been sold while the media was uploading
+    let recipe = owned_recipe(media.recipe_id, user.id)?;
+    let mut ids = recipe.media.clone().unwrap_or_default();
+    ids.push(media.id);
+    let recipe = Recipe {
+        media: Some(ids),
+        ..recipe
+    };
+    check_size(&recipe, "recipe with this media")?;
+    RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, recipe));
+    pages::certify_recipe(media.recipe_id);
+    let media = Media {
+        is_complete: true,
+        ..media
+    };
+    store(&media);
+    certify(&media);
+    Ok(media)
+}
+
+// remove a media from its recipe and free its bytes
+#[ic_cdk::update]
+fn delete_media(media_id: u64) -> Result<Media, Error> {
+    let user = caller_user()?;
+    let media = get_media(media_id)?;
+    // unfinished uploads can be dropped by their uploader, finished media by
+    // whoever owns the recipe now
+    if media.uploader_id != user.id || media.is_complete {
+        owned_recipe(media.recipe_id, user.id)?;
+    }
+    if let Some(recipe) = RECIPE_STORAGE.with(|s| s.borrow().get(&media.recipe_id)) {
+        let ids = recipe
+            .media
+            .clone()
+            .map(|ids| ids.into_iter().filter(|id| *id != media_id).collect());
+        let recipe = Recipe {
+            media: ids,
+            ..recipe
+        };
+        RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, recipe));
+        pages::certify_recipe(media.recipe_id);
+    }
+    discard(&media);
+    if media.is_complete {
+        http::uncertify(&path(media_id));
+    }
+    Ok(media)
+}
+
+// get the finished images and videos of a recipe
+#[ic_cdk::query]
+fn get_recipe_media(recipe_id: u64) -> Result<Vec<MediaRef>, Error> {
+    match RECIPE_STORAGE.with(|s| s.borrow().get(&recipe_id)) {
+        Some(recipe) => Ok(refs(&recipe)),
+        None => Err(Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        }),
+    }
+}
+
+// the first chunk of a finished media, with a token to stream the rest
+pub(crate) fn serve(media_id: u64) -> HttpResponse {
+    let media = match MEDIA_STORAGE.with(|s| s.borrow().get(&media_id)) {
+        Some(media) if media.is_complete => media,
+        _ => return http::not_found(),
+    };
+    let first = CHUNK_SIZE.min(media.size);
+    let mut headers = vec![
+        ("Content-Type".to_string(), media.content_type.clone()),
+        ("Content-Length".to_string(), media.size.to_string()),
+        // media never change, a new upload gets a new id
+        (
+            "Cache-Control".to_string(),
+            "public, max-age=31536000, immutable".to_string(),
+        ),
+        ("ETag".to_string(), format!("\"{}\"", hex(&media.sha256))),
+    ];
+    // the gateway checks the hash of the whole body once it is streamed
+    headers.extend(http::certificate_header(&path(media_id)));
+    let token = (first < media.size).then_some(StreamingToken { media_id, index: 1 });
+    HttpResponse::streamed(200, headers, read(media.offset, first), token)
+}
+
+// the chunk of a media a streaming token points at
+pub(crate) fn next_chunk(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    let Some(media) = MEDIA_STORAGE.with(|s| s.borrow().get(&token.media_id)) else {
+        return StreamingCallbackHttpResponse {
+            body: vec![],
+            token: None,
+        };
+    };
+    let start = (token.index * CHUNK_SIZE).min(media.size);
+    let end = (start + CHUNK_SIZE).min(media.size);
+    StreamingCallbackHttpResponse {
+        body: read(media.offset + start, end - start),
+        token: (end < media.size).then(|| StreamingToken {
+            index: token.index + 1,
+            ..token
+        }),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
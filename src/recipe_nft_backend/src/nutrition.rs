@@ -0,0 +1,345 @@
+use crate::body::{Ingredient, RecipeBody, Unit};
+use crate::scaling;
+use crate::{caller_contract_owner, content, Error, Recipe, NUTRITION_STORAGE};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use validator::Validate;
+
+// grams in a pinch of a dry ingredient
+const GRAMS_PER_PINCH: f64 = 0.36;
+
+// The allergens food labels must declare
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum Allergen {
+    Gluten,
+    Crustaceans,
+    Eggs,
+    Fish,
+    Peanuts,
+    Soy,
+    Milk,
+    TreeNuts,
+    Celery,
+    Mustard,
+    Sesame,
+    Sulphites,
+    Lupin,
+    Molluscs,
+}
+
+// Nutrition facts of an ingredient, per 100g
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct NutritionFacts {
+    #[validate(length(min = 1, max = 100))]
+    name: String,
+    #[validate(range(min = 0.0, max = 900.0))]
+    calories: f64,
+    #[validate(range(min = 0.0, max = 100.0))]
+    protein_g: f64,
+    #[validate(range(min = 0.0, max = 100.0))]
+    carbs_g: f64,
+    #[validate(range(min = 0.0, max = 100.0))]
+    fat_g: f64,
+    allergens: Vec<Allergen>,
+    // weight of one piece, for ingredients counted rather than weighed
+    #[validate(range(min = 0.0))]
+    grams_per_piece: Option<f64>,
+    // density for ingredients measured by volume, water (1.0) when not given
+    #[validate(range(min = 0.0, max = 25.0))]
+    grams_per_ml: Option<f64>,
+}
+
+impl Storable for NutritionFacts {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for NutritionFacts {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Calories and macros of one serving of a recipe, with the allergens in it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Nutrition {
+    pub(crate) calories: f64,
+    pub(crate) protein_g: f64,
+    pub(crate) carbs_g: f64,
+    pub(crate) fat_g: f64,
+    pub(crate) allergens: Vec<Allergen>,
+    // false when some ingredients were not in the nutrition table
+    pub(crate) is_complete: bool,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// the table key of an ingredient name
+fn name_key(name: &str) -> u64 {
+    let hash = Sha256::digest(normalize(name).as_bytes());
+    let mut key = [0u8; 8];
+    key.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(key)
+}
+
+// an ingredient in the table under a name or its singular
+fn exact(name: &str) -> Option<NutritionFacts> {
+    let get = |name: &str| NUTRITION_STORAGE.with(|s| s.borrow().get(&name_key(name)));
+    get(name).or_else(|| name.strip_suffix('s').and_then(get))
+}
+
+// find an ingredient in the table by its name or its singular, else by the
+// longest run of whole words naming a table entry, so "red onions" matches
+// "onion" but "eggplant" never matches "egg". Names with runs naming different
+// entries, like "coconut milk" when both "coconut" and "milk" are listed, are
+// not guessed at
+fn lookup(name: &str) -> Option<NutritionFacts> {
+    let name = normalize(name);
+    if let Some(facts) = exact(&name) {
+        return Some(facts);
+    }
+    let words: Vec<&str> = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    for len in (1..words.len()).rev() {
+        let mut found: Vec<NutritionFacts> = vec![];
+        for run in words.windows(len) {
+            if let Some(facts) = exact(&run.join(" ")) {
+                if !found.iter().any(|other| other.name == facts.name) {
+                    found.push(facts);
+                }
+            }
+        }
+        match found.len() {
+            0 => continue,
+            1 => return found.pop(),
+            _ => return None,
+        }
+    }
+    None
+}
+
+// the weight of an ingredient in grams, None when it can not be known
+fn grams(ingredient: &Ingredient, facts: &NutritionFacts) -> Option<f64> {
+    let quantity = ingredient.quantity?;
+    match ingredient.unit {
+        Some(Unit::Pinch) => Some(quantity * GRAMS_PER_PINCH),
+        Some(unit) if scaling::is_weight(unit) => Some(scaling::to_base(quantity, unit)),
+        Some(Unit::Piece) | None => facts.grams_per_piece.map(|grams| quantity * grams),
+        Some(unit) => Some(scaling::to_base(quantity, unit) * facts.grams_per_ml.unwrap_or(1.0)),
+    }
+}
+
+// compute the nutrition of one serving from the ingredient table
+pub(crate) fn compute(body: &RecipeBody, servings: u32) -> Nutrition {
+    let mut total = Nutrition {
+        is_complete: true,
+        ..Nutrition::default()
+    };
+    for ingredient in &body.ingredients {
+        let Some(facts) = lookup(&ingredient.name) else {
+            total.is_complete = false;
+            continue;
+        };
+        for allergen in &facts.allergens {
+            if !total.allergens.contains(allergen) {
+                total.allergens.push(*allergen);
+            }
+        }
+        // ingredients used to taste add allergens but no measurable nutrition
+        if ingredient.quantity.is_none() {
+            continue;
+        }
+        let Some(grams) = grams(ingredient, &facts) else {
+            total.is_complete = false;
+            continue;
+        };
+        let share = grams / 100.0;
+        total.calories += facts.calories * share;
+        total.protein_g += facts.protein_g * share;
+        total.carbs_g += facts.carbs_g * share;
+        total.fat_g += facts.fat_g * share;
+    }
+    total.allergens.sort();
+
+    let servings = servings.max(1) as f64;
+    let per_serving = |value: f64| (value / servings * 10.0).round() / 10.0;
+    Nutrition {
+        calories: per_serving(total.calories),
+        protein_g: per_serving(total.protein_g),
+        carbs_g: per_serving(total.carbs_g),
+        fat_g: per_serving(total.fat_g),
+        ..total
+    }
+}
+
+// the nutrition of an edited recipe, recomputed when its body or servings change
+pub(crate) fn after_edit(
+    recipe: &Recipe,
+    body: Option<&RecipeBody>,
+    servings: Option<u32>,
+) -> Result<Option<Nutrition>, Error> {
+    if body.is_none() && servings.is_none() {
+        return Ok(recipe.nutrition.clone());
+    }
+    let Some(servings) = servings.or(recipe.servings) else {
+        return Ok(None);
+    };
+    let nutrition = match body {
+        Some(body) => compute(body, servings),
+        None => compute(&content::body_of(recipe)?, servings),
+    };
+    Ok(Some(nutrition))
+}
+
+// add or replace entries of the ingredient nutrition table, keyed by name
+#[ic_cdk::update]
+fn load_nutrition(entries: Vec<NutritionFacts>) -> Result<u64, Error> {
+    caller_contract_owner()?;
+    for entry in &entries {
+        if let Err(e) = entry.validate() {
+            return Err(Error::InvalidPayload {
+                msg: format!("{}: {}", entry.name, e),
+            });
+        }
+    }
+    let count = entries.len() as u64;
+    for entry in entries {
+        NUTRITION_STORAGE.with(|s| s.borrow_mut().insert(name_key(&entry.name), entry));
+    }
+    Ok(count)
+}
+
+// remove an ingredient from the nutrition table
+#[ic_cdk::update]
+fn remove_nutrition(name: String) -> Result<NutritionFacts, Error> {
+    caller_contract_owner()?;
+    NUTRITION_STORAGE
+        .with(|s| s.borrow_mut().remove(&name_key(&name)))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("no nutrition facts for ingredient: {}", name),
+        })
+}
+
+// get the nutrition facts of an ingredient as it would be matched in a recipe
+#[ic_cdk::query]
+fn get_nutrition(name: String) -> Result<NutritionFacts, Error> {
+    lookup(&name).ok_or_else(|| Error::NotFound {
+        msg: format!("no nutrition facts for ingredient: {}", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(name: &str, grams_per_piece: Option<f64>, grams_per_ml: Option<f64>) {
+        let facts = NutritionFacts {
+            name: name.to_string(),
+            calories: 100.0,
+            grams_per_piece,
+            grams_per_ml,
+            ..NutritionFacts::default()
+        };
+        NUTRITION_STORAGE.with(|s| s.borrow_mut().insert(name_key(name), facts));
+    }
+
+    fn ingredient(name: &str, quantity: f64, unit: Option<Unit>) -> Ingredient {
+        Ingredient {
+            name: name.to_string(),
+            quantity: Some(quantity),
+            unit,
+            notes: None,
+        }
+    }
+
+    fn found(name: &str) -> Option<String> {
+        lookup(name).map(|facts| facts.name)
+    }
+
+    #[test]
+    fn lookup_normalises_names_and_plurals() {
+        add("onion", Some(110.0), None);
+        assert_eq!(found("onion").as_deref(), Some("onion"));
+        assert_eq!(found("  Onion ").as_deref(), Some("onion"));
+        assert_eq!(found("ONIONS").as_deref(), Some("onion"));
+        assert_eq!(found("red onions").as_deref(), Some("onion"));
+    }
+
+    #[test]
+    fn lookup_matches_whole_words_only() {
+        add("egg", Some(50.0), None);
+        add("coconut", None, None);
+        add("milk", None, Some(1.03));
+        assert_eq!(found("free range eggs").as_deref(), Some("egg"));
+        assert!(found("eggplant").is_none());
+        // runs naming different entries are not guessed at
+        assert!(found("coconut milk").is_none());
+    }
+
+    #[test]
+    fn lookup_misses_unknown_ingredients() {
+        add("flour", None, None);
+        assert!(found("saffron").is_none());
+        assert!(found("").is_none());
+
+        let body = RecipeBody {
+            ingredients: vec![
+                ingredient("flour", 200.0, Some(Unit::Gram)),
+                ingredient("saffron", 1.0, Some(Unit::Pinch)),
+            ],
+            ..RecipeBody::default()
+        };
+        let nutrition = compute(&body, 2);
+        assert!(!nutrition.is_complete);
+        assert_eq!(nutrition.calories, 100.0);
+    }
+
+    #[test]
+    fn grams_converts_weights_volumes_and_pieces() {
+        let facts = |grams_per_piece, grams_per_ml| NutritionFacts {
+            grams_per_piece,
+            grams_per_ml,
+            ..NutritionFacts::default()
+        };
+        let plain = facts(None, None);
+        assert_eq!(
+            grams(&ingredient("flour", 1.0, Some(Unit::Kilogram)), &plain),
+            Some(1000.0)
+        );
+        assert_eq!(
+            grams(&ingredient("salt", 2.0, Some(Unit::Pinch)), &plain),
+            Some(2.0 * GRAMS_PER_PINCH)
+        );
+        // volumes are weighed at the ingredient's density, water when unknown
+        assert_eq!(
+            grams(&ingredient("water", 250.0, Some(Unit::Milliliter)), &plain),
+            Some(250.0)
+        );
+        assert_eq!(
+            grams(
+                &ingredient("oil", 100.0, Some(Unit::Milliliter)),
+                &facts(None, Some(0.92))
+            ),
+            Some(92.0)
+        );
+        // counted ingredients need the weight of a piece
+        assert_eq!(
+            grams(&ingredient("egg", 2.0, None), &facts(Some(50.0), None)),
+            Some(100.0)
+        );
+        assert!(grams(&ingredient("egg", 2.0, Some(Unit::Piece)), &plain).is_none());
+    }
+}
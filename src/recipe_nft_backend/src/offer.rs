@@ -0,0 +1,401 @@
+use crate::auction::MAX_AUCTION_DURATION;
+use crate::history::{self, TransactionKind};
+use crate::settlement::{self, Settlement};
+use crate::{
+    caller_user, credit_balance, debit_balance, fund_user, ledger, marketplace_config, Error,
+    ID_COUNTER, OFFER_STORAGE, RECIPE_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum OfferStatus {
+    Open,
+    Countered,
+    Accepted,
+    Rejected,
+    Withdrawn,
+    Expired,
+}
+
+// An offer to buy a recipe whether or not it is listed. The offered amount is
+// held in escrow, taken out of the buyer's balance until the offer is accepted
+// or closed. The owner may answer with a counter price instead
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Offer {
+    id: u64,
+    recipe_id: u64,
+    buyer_id: u64,
+    amount: u64,
+    counter_amount: Option<u64>,
+    created_at: u64,
+    expires_at: u64,
+    status: OfferStatus,
+}
+
+impl Offer {
+    fn is_pending(&self) -> bool {
+        matches!(self.status, OfferStatus::Open | OfferStatus::Countered)
+    }
+}
+
+impl Storable for Offer {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Offer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct OfferPayload {
+    recipe_id: u64,
+    amount: u64,
+    // nanoseconds since the epoch
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CounterOfferPayload {
+    offer_id: u64,
+    amount: u64,
+}
+
+fn get(offer_id: u64) -> Result<Offer, Error> {
+    OFFER_STORAGE
+        .with(|s| s.borrow().get(&offer_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("offer of id: {} not found", offer_id),
+        })
+}
+
+fn store(offer: &Offer) {
+    OFFER_STORAGE.with(|s| s.borrow_mut().insert(offer.id, offer.clone()));
+}
+
+// a pending offer that has not reached its deadline
+fn get_pending(offer_id: u64) -> Result<Offer, Error> {
+    let offer = get(offer_id)?;
+    if !offer.is_pending() || ic_cdk::api::time() >= offer.expires_at {
+        return Err(Error::InvalidPayload {
+            msg: "This offer is no longer open".to_string(),
+        });
+    }
+    Ok(offer)
+}
+
+// the offer if the caller owns the recipe it is for
+fn owner_offer(offer_id: u64) -> Result<Offer, Error> {
+    let user = caller_user()?;
+    let offer = get_pending(offer_id)?;
+    match RECIPE_STORAGE.with(|s| s.borrow().get(&offer.recipe_id)) {
+        Some(recipe) if recipe.user_id == user.id => Ok(offer),
+        _ => Err(Error::Unauthorized {
+            msg: "Only the owner of the recipe can answer this offer".to_string(),
+        }),
+    }
+}
+
+// the offer if the caller made it
+fn buyer_offer(offer_id: u64) -> Result<Offer, Error> {
+    let user = caller_user()?;
+    let offer = get_pending(offer_id)?;
+    if offer.buyer_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the buyer can change this offer".to_string(),
+        });
+    }
+    Ok(offer)
+}
+
+// close an offer, releasing its escrow back to the buyer
+fn close(offer: Offer, status: OfferStatus) -> Result<Offer, Error> {
+    credit_balance(offer.buyer_id, offer.amount)?;
+    history::record(
+        TransactionKind::BidRefund,
+        Some(offer.recipe_id),
+        None,
+        Some(offer.buyer_id),
+        Some(offer.amount),
+    );
+    let offer = Offer { status, ..offer };
+    store(&offer);
+    Ok(offer)
+}
+
+// expire the offer once its deadline has passed
+fn schedule_expiry(offer: &Offer) {
+    let delay = offer.expires_at.saturating_sub(ic_cdk::api::time());
+    let offer_id = offer.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || {
+        if let Ok(offer) = get(offer_id) {
+            if offer.is_pending() && close(offer, OfferStatus::Expired).is_err() {
+                ic_cdk::println!("Offer {} could not be refunded", offer_id);
+            }
+        }
+    });
+}
+
+// timers do not survive upgrades, reschedule expiry of every pending offer
+pub(crate) fn reschedule_pending_offers() {
+    let pending: Vec<Offer> = OFFER_STORAGE.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .map(|(_, o)| o)
+            .filter(|o| o.is_pending())
+            .collect()
+    });
+    for offer in pending {
+        schedule_expiry(&offer);
+    }
+}
+
+// check that a user may make an offer on a recipe
+fn check_offer(user_id: u64, payload: &OfferPayload) -> Result<(), Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    if recipe.is_community {
+        return Err(Error::InvalidPayload {
+            msg: "This is a community recipe, you can not buy it".to_string(),
+        });
+    }
+    if recipe.user_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not buy your own recipe".to_string(),
+        });
+    }
+    let now = ic_cdk::api::time();
+    if payload.expires_at <= now || payload.expires_at - now > MAX_AUCTION_DURATION {
+        return Err(Error::InvalidPayload {
+            msg: "Offers must expire in the future and within 30 days".to_string(),
+        });
+    }
+    marketplace_config().check_price(payload.amount)?;
+    let duplicate = OFFER_STORAGE.with(|offers| {
+        offers.borrow().iter().any(|(_, o)| {
+            o.recipe_id == payload.recipe_id && o.buyer_id == user_id && o.is_pending()
+        })
+    });
+    if duplicate {
+        return Err(Error::InvalidPayload {
+            msg: "You already have an open offer on this recipe".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// offer to buy a recipe, escrowing the amount from the caller's balance. Any
+// shortfall is pulled from their ledger account first
+#[ic_cdk::update]
+async fn make_offer(payload: OfferPayload) -> Result<Offer, Error> {
+    let user = caller_user()?;
+    check_offer(user.id, &payload)?;
+    settlement::check_room(&user)?;
+
+    if user.balance < payload.amount {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = payload.amount - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the offer, the recipe or balances may have changed during the ledger call
+    let user = caller_user()?;
+    check_offer(user.id, &payload)?;
+    debit_balance(user.id, payload.amount)?;
+    history::record(
+        TransactionKind::Bid,
+        Some(payload.recipe_id),
+        Some(user.id),
+        None,
+        Some(payload.amount),
+    );
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let offer = Offer {
+        id,
+        recipe_id: payload.recipe_id,
+        buyer_id: user.id,
+        amount: payload.amount,
+        counter_amount: None,
+        created_at: ic_cdk::api::time(),
+        expires_at: payload.expires_at,
+        status: OfferStatus::Open,
+    };
+    store(&offer);
+    schedule_expiry(&offer);
+    Ok(offer)
+}
+
+// sell the recipe to the buyer at the escrowed amount through the regular
+// purchase settlement. The escrow is released just before the purchase and
+// taken back if the purchase fails, leaving the offer pending
+fn settle(offer: Offer) -> Result<Offer, Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&offer.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", offer.recipe_id),
+        })?;
+    credit_balance(offer.buyer_id, offer.amount)?;
+    history::record(
+        TransactionKind::BidRefund,
+        Some(offer.recipe_id),
+        None,
+        Some(offer.buyer_id),
+        Some(offer.amount),
+    );
+    if let Err(e) = Settlement::purchase(recipe, offer.buyer_id, offer.amount).apply() {
+        debit_balance(offer.buyer_id, offer.amount)?;
+        history::record(
+            TransactionKind::Bid,
+            Some(offer.recipe_id),
+            Some(offer.buyer_id),
+            None,
+            Some(offer.amount),
+        );
+        return Err(e);
+    }
+    let offer = Offer {
+        status: OfferStatus::Accepted,
+        ..offer
+    };
+    store(&offer);
+    Ok(offer)
+}
+
+// accept an offer on the caller's recipe, selling it at the offered amount
+#[ic_cdk::update]
+fn accept_offer(offer_id: u64) -> Result<Offer, Error> {
+    let offer = owner_offer(offer_id)?;
+    settle(offer)
+}
+
+// reject an offer on the caller's recipe, refunding the buyer
+#[ic_cdk::update]
+fn reject_offer(offer_id: u64) -> Result<Offer, Error> {
+    let offer = owner_offer(offer_id)?;
+    close(offer, OfferStatus::Rejected)
+}
+
+// answer an offer on the caller's recipe with a higher price the buyer can accept
+#[ic_cdk::update]
+fn counter_offer(payload: CounterOfferPayload) -> Result<Offer, Error> {
+    let offer = owner_offer(payload.offer_id)?;
+    if payload.amount <= offer.amount {
+        return Err(Error::InvalidPayload {
+            msg: "A counter offer must ask for more than the offer".to_string(),
+        });
+    }
+    let offer = Offer {
+        counter_amount: Some(payload.amount),
+        status: OfferStatus::Countered,
+        ..offer
+    };
+    store(&offer);
+    Ok(offer)
+}
+
+// check that the caller may accept the counter to their offer, returning the
+// offer and how much more needs to be escrowed
+fn check_counter(offer_id: u64) -> Result<(Offer, u64), Error> {
+    let offer = buyer_offer(offer_id)?;
+    match offer.counter_amount {
+        Some(counter_amount) if offer.status == OfferStatus::Countered => {
+            Ok((offer.clone(), counter_amount - offer.amount))
+        }
+        _ => Err(Error::InvalidPayload {
+            msg: "This offer has not been countered".to_string(),
+        }),
+    }
+}
+
+// accept the owner's counter to the caller's offer, escrowing the difference
+// and buying the recipe at the counter price
+#[ic_cdk::update]
+async fn accept_counter_offer(offer_id: u64) -> Result<Offer, Error> {
+    let user = caller_user()?;
+    let (_, needed) = check_counter(offer_id)?;
+    settlement::check_room(&user)?;
+
+    if user.balance < needed {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = needed - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the counter, the offer or balances may have changed during the ledger call
+    let (offer, needed) = check_counter(offer_id)?;
+    debit_balance(offer.buyer_id, needed)?;
+    history::record(
+        TransactionKind::Bid,
+        Some(offer.recipe_id),
+        Some(offer.buyer_id),
+        None,
+        Some(needed),
+    );
+    let offer = Offer {
+        amount: offer.amount + needed,
+        status: OfferStatus::Open,
+        ..offer
+    };
+    store(&offer);
+    settle(offer)
+}
+
+// withdraw the caller's offer, refunding it
+#[ic_cdk::update]
+fn withdraw_offer(offer_id: u64) -> Result<Offer, Error> {
+    let offer = buyer_offer(offer_id)?;
+    close(offer, OfferStatus::Withdrawn)
+}
+
+#[ic_cdk::query]
+fn get_offer(offer_id: u64) -> Result<Offer, Error> {
+    get(offer_id)
+}
+
+// get the pending offers on a recipe
+#[ic_cdk::query]
+fn get_recipe_offers(recipe_id: u64) -> Vec<Offer> {
+    OFFER_STORAGE.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .map(|(_, o)| o)
+            .filter(|o| o.recipe_id == recipe_id && o.is_pending())
+            .collect()
+    })
+}
+
+// get every offer the caller has made
+#[ic_cdk::query]
+fn get_my_offers() -> Result<Vec<Offer>, Error> {
+    let user = caller_user()?;
+    Ok(OFFER_STORAGE.with(|offers| {
+        offers
+            .borrow()
+            .iter()
+            .map(|(_, o)| o)
+            .filter(|o| o.buyer_id == user.id)
+            .collect()
+    }))
+}
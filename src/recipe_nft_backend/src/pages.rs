@@ -0,0 +1,293 @@
+use crate::content::RecipePreview;
+use crate::{http, icrc7, media, Recipe, User, RECIPE_STORAGE, USER_STORAGE};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+// pages rendered and certified per timer when certifying every page
+const CERTIFY_BATCH_SIZE: usize = 100;
+
+// A page rendered for the http gateway
+pub(crate) struct Page {
+    pub(crate) content_type: &'static str,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Page {
+    fn html(body: String) -> Self {
+        Page {
+            content_type: "text/html; charset=utf-8",
+            body: body.into_bytes(),
+        }
+    }
+}
+
+// render the page served at a path, None when there is none
+pub(crate) fn render(path: &str) -> Option<Page> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["recipes", id] => match id.strip_suffix(".json") {
+            Some(id) => recipe_json(get_recipe(id)?),
+            None => Some(recipe_html(get_recipe(id)?)),
+        },
+        ["users", id] => Some(user_html(get_user(id)?)),
+        _ => None,
+    }
+}
+
+// render the page at a path again and certify it, or stop certifying it when
+// it is gone. Only update calls can certify
+fn certify(path: &str) {
+    match render(path) {
+        Some(page) => http::certify(path, Sha256::digest(&page.body).into()),
+        None => http::uncertify(path),
+    }
+}
+
+// certify the pages showing a recipe: its own, its JSON and its owner's page.
+// Every update changing a recipe calls this, queries only serve certified pages
+pub(crate) fn certify_recipe(recipe_id: u64) {
+    certify(&format!("/recipes/{}", recipe_id));
+    certify(&format!("/recipes/{}.json", recipe_id));
+    if let Some(recipe) = RECIPE_STORAGE.with(|s| s.borrow().get(&recipe_id)) {
+        certify_user(recipe.user_id);
+    }
+}
+
+pub(crate) fn certify_user(user_id: u64) {
+    certify(&format!("/users/{}", user_id));
+}
+
+// certified hashes live on the heap, certify every page again after an
+// upgrade. Pages are rendered a batch per timer, so no single message has to
+// render them all
+pub(crate) fn certify_all() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || certify_recipes_from(0));
+}
+
+fn certify_recipes_from(start: u64) {
+    let ids: Vec<u64> = RECIPE_STORAGE.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take(CERTIFY_BATCH_SIZE)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in &ids {
+        certify(&format!("/recipes/{}", id));
+        certify(&format!("/recipes/{}.json", id));
+    }
+    match ids.last() {
+        Some(last) if ids.len() == CERTIFY_BATCH_SIZE => {
+            let next = last + 1;
+            ic_cdk_timers::set_timer(Duration::ZERO, move || certify_recipes_from(next));
+        }
+        _ => {
+            ic_cdk_timers::set_timer(Duration::ZERO, || certify_users_from(0));
+        }
+    }
+}
+
+fn certify_users_from(start: u64) {
+    let ids: Vec<u64> = USER_STORAGE.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take(CERTIFY_BATCH_SIZE)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in &ids {
+        certify_user(*id);
+    }
+    if let Some(last) = ids.last() {
+        if ids.len() == CERTIFY_BATCH_SIZE {
+            let next = last + 1;
+            ic_cdk_timers::set_timer(Duration::ZERO, move || certify_users_from(next));
+        }
+    }
+}
+
+fn get_recipe(id: &str) -> Option<Recipe> {
+    let id: u64 = id.parse().ok()?;
+    RECIPE_STORAGE.with(|s| s.borrow().get(&id))
+}
+
+fn get_user(id: &str) -> Option<User> {
+    let id: u64 = id.parse().ok()?;
+    USER_STORAGE.with(|s| s.borrow().get(&id))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// the head of a page, with the OpenGraph tags link previews are built from
+fn head(title: &str, description: &str, og_type: &str, path: &str, image: Option<&str>) -> String {
+    let mut tags = vec![
+        format!("<title>{} · {}</title>", escape(title), icrc7::NAME),
+        format!(
+            "<meta name=\"description\" content=\"{}\">",
+            escape(description)
+        ),
+        format!(
+            "<meta property=\"og:site_name\" content=\"{}\">",
+            icrc7::NAME
+        ),
+        format!("<meta property=\"og:type\" content=\"{}\">", og_type),
+        format!("<meta property=\"og:title\" content=\"{}\">", escape(title)),
+        format!(
+            "<meta property=\"og:description\" content=\"{}\">",
+            escape(description)
+        ),
+        format!(
+            "<meta property=\"og:url\" content=\"{}{}\">",
+            http::base_url(),
+            path
+        ),
+    ];
+    match image {
+        Some(image) => {
+            tags.push(format!(
+                "<meta property=\"og:image\" content=\"{}\">",
+                escape(image)
+            ));
+            tags.push("<meta name=\"twitter:card\" content=\"summary_large_image\">".to_string());
+        }
+        None => tags.push("<meta name=\"twitter:card\" content=\"summary\">".to_string()),
+    }
+    format!(
+        "<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n{}\n</head>",
+        tags.join("\n")
+    )
+}
+
+fn document(head: String, main: String) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n{}\n<body>\n<main>\n{}\n</main>\n</body>\n</html>\n",
+        head, main
+    )
+}
+
+// only the public preview of a recipe is rendered, its body stays encrypted
+fn recipe_html(recipe: Recipe) -> Page {
+    let path = format!("/recipes/{}", recipe.id);
+    let media = media::refs(&recipe);
+    let image = recipe
+        .image_url
+        .clone()
+        .or_else(|| media::cover_url(&media));
+    let teaser = recipe.teaser.clone().unwrap_or_default();
+
+    let mut main = vec![
+        format!("<h1>{}</h1>", escape(&recipe.title)),
+        format!("<p>{}</p>", escape(&recipe.category)),
+    ];
+    if let Some(image) = &image {
+        main.push(format!(
+            "<img src=\"{}\" alt=\"{}\">",
+            escape(image),
+            escape(&recipe.title)
+        ));
+    }
+    if !teaser.is_empty() {
+        main.push(format!("<p>{}</p>", escape(&teaser)));
+    }
+    let mut facts = vec![];
+    if let Some(servings) = recipe.servings {
+        facts.push(format!("<li>Serves {}</li>", servings));
+    }
+    if let Some(prep_minutes) = recipe.prep_minutes {
+        facts.push(format!("<li>Prep {} min</li>", prep_minutes));
+    }
+    if let Some(cook_minutes) = recipe.cook_minutes {
+        facts.push(format!("<li>Cook {} min</li>", cook_minutes));
+    }
+    if let Some(nutrition) = &recipe.nutrition {
+        facts.push(format!(
+            "<li>{} kcal per serving</li>",
+            nutrition.calories.round()
+        ));
+    }
+    if !facts.is_empty() {
+        main.push(format!("<ul>\n{}\n</ul>", facts.join("\n")));
+    }
+    main.push(if recipe.is_community {
+        "<p>Community recipe</p>".to_string()
+    } else if recipe.is_for_sale {
+        format!("<p>For sale at {}</p>", recipe.price)
+    } else {
+        "<p>Not for sale</p>".to_string()
+    });
+    let creator = recipe
+        .creator_id
+        .and_then(|id| USER_STORAGE.with(|s| s.borrow().get(&id)));
+    if let Some(creator) = creator {
+        main.push(format!(
+            "<p>By <a href=\"/users/{}\">{}</a></p>",
+            creator.id,
+            escape(&creator.name)
+        ));
+    }
+    main.push(format!(
+        "<p><a href=\"{}.json\" type=\"application/json\">JSON</a></p>",
+        path
+    ));
+
+    let head = head(&recipe.title, &teaser, "article", &path, image.as_deref());
+    Page::html(document(head, main.join("\n")))
+}
+
+fn recipe_json(recipe: Recipe) -> Option<Page> {
+    let body = serde_json::to_vec(&RecipePreview::from(recipe)).ok()?;
+    Some(Page {
+        content_type: "application/json",
+        body,
+    })
+}
+
+fn user_html(user: User) -> Page {
+    let path = format!("/users/{}", user.id);
+    let recipes: Vec<Recipe> = user
+        .recipes
+        .iter()
+        .filter_map(|id| RECIPE_STORAGE.with(|s| s.borrow().get(id)))
+        .collect();
+    let description = format!("{} recipes on {}", recipes.len(), icrc7::NAME);
+    let image = recipes.iter().find_map(|recipe| {
+        recipe
+            .image_url
+            .clone()
+            .or_else(|| media::cover_url(&media::refs(recipe)))
+    });
+
+    let mut main = vec![
+        format!("<h1>{}</h1>", escape(&user.name)),
+        format!("<p>{}</p>", escape(&description)),
+    ];
+    if !recipes.is_empty() {
+        let items: Vec<String> = recipes
+            .iter()
+            .map(|recipe| {
+                format!(
+                    "<li><a href=\"/recipes/{}\">{}</a></li>",
+                    recipe.id,
+                    escape(&recipe.title)
+                )
+            })
+            .collect();
+        main.push(format!("<ul>\n{}\n</ul>", items.join("\n")));
+    }
+
+    let head = head(&user.name, &description, "profile", &path, image.as_deref());
+    Page::html(document(head, main.join("\n")))
+}
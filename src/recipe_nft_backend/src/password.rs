@@ -0,0 +1,105 @@
+use crate::Error;
+use sha2::{Digest, Sha256};
+
+// Number of SHA-256 rounds applied when hashing a password
+const HASH_ITERATIONS: u32 = 10_000;
+
+// Salted, iterated SHA-256 password hash. Records written before hashing was
+// introduced are loaded with `iterations == 0` and the plaintext in `hash`
+// until `migrate` rehashes them after an upgrade.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PasswordHash {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+    iterations: u32,
+}
+
+impl PasswordHash {
+    // hash a password with the given salt
+    pub(crate) fn new(password: &str, salt: Vec<u8>) -> Self {
+        let hash = hash_password(password.as_bytes(), &salt, HASH_ITERATIONS);
+        PasswordHash {
+            salt,
+            hash,
+            iterations: HASH_ITERATIONS,
+        }
+    }
+
+    // wrap a plaintext password loaded from a legacy record
+    pub(crate) fn legacy(password: String) -> Self {
+        PasswordHash {
+            salt: vec![],
+            hash: password.into_bytes(),
+            iterations: 0,
+        }
+    }
+
+    pub(crate) fn is_legacy(&self) -> bool {
+        self.iterations == 0
+    }
+
+    // rehash a legacy plaintext password, hashed passwords are returned unchanged
+    pub(crate) fn migrate(self, salt: Vec<u8>) -> Self {
+        if !self.is_legacy() {
+            return self;
+        }
+        let hash = hash_password(&self.hash, &salt, HASH_ITERATIONS);
+        PasswordHash {
+            salt,
+            hash,
+            iterations: HASH_ITERATIONS,
+        }
+    }
+
+    // check a password against the stored hash in constant time
+    pub(crate) fn verify(&self, password: &str) -> bool {
+        if self.is_legacy() {
+            return constant_time_eq(password.as_bytes(), &self.hash);
+        }
+        let hash = hash_password(password.as_bytes(), &self.salt, self.iterations);
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+fn hash_password(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    for _ in 1..iterations {
+        hash = Sha256::new()
+            .chain_update(hash)
+            .chain_update(salt)
+            .chain_update(password)
+            .finalize();
+    }
+    hash.to_vec()
+}
+
+// compare two byte slices without short-circuiting on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Get a fresh salt from the management canister's randomness
+pub(crate) async fn new_salt() -> Result<Vec<u8>, Error> {
+    match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((bytes,)) => Ok(bytes),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("Could not get randomness for salt: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Derive a per-record salt from a random seed, used when migrating many
+// records with a single call to raw_rand
+pub(crate) fn derive_salt(seed: &[u8], id: u64) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(seed)
+        .chain_update(id.to_be_bytes())
+        .finalize()
+        .to_vec()
+}
@@ -0,0 +1,252 @@
+use crate::history::{self, TransactionKind};
+use crate::pages;
+use crate::{
+    caller_user, credit_balance, debit_balance, fund_user, ledger, Error, Recipe, ID_COUNTER,
+    RECIPE_STORAGE, RENTAL_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+use validator::Validate;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Time-boxed read access to a recipe paid for by the day. The owner keeps the
+// recipe and is paid the fee up front
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Rental {
+    id: u64,
+    recipe_id: u64,
+    renter_id: u64,
+    owner_id: u64,
+    fee: u64,
+    start_time: u64,
+    end_time: u64,
+    // false once the rental has been revoked at its end time
+    is_active: bool,
+}
+
+impl Rental {
+    fn grants_access(&self, now: u64) -> bool {
+        self.is_active && now < self.end_time
+    }
+}
+
+impl Storable for Rental {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Rental {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RentPayload {
+    recipe_id: u64,
+    #[validate(range(min = 1, max = 30))]
+    days: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RentalPricePayload {
+    recipe_id: u64,
+    // None stops the recipe from being rented
+    price_per_day: Option<u64>,
+}
+
+// the rental giving a user access to a recipe right now, if any
+pub(crate) fn active_rental(user_id: u64, recipe_id: u64) -> Option<Rental> {
+    let now = ic_cdk::api::time();
+    RENTAL_STORAGE.with(|rentals| {
+        rentals
+            .borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .find(|r| r.renter_id == user_id && r.recipe_id == recipe_id && r.grants_access(now))
+    })
+}
+
+// revoke the rental once its time is up
+fn schedule_revocation(rental: &Rental) {
+    let delay = rental.end_time.saturating_sub(ic_cdk::api::time());
+    let rental_id = rental.id;
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || {
+        if let Some(rental) = RENTAL_STORAGE.with(|s| s.borrow().get(&rental_id)) {
+            let revoked = Rental {
+                is_active: false,
+                ..rental
+            };
+            RENTAL_STORAGE.with(|s| s.borrow_mut().insert(rental_id, revoked));
+        }
+    });
+}
+
+// timers do not survive upgrades, reschedule revocation of every active rental
+pub(crate) fn reschedule_active_rentals() {
+    let active: Vec<Rental> = RENTAL_STORAGE.with(|rentals| {
+        rentals
+            .borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.is_active)
+            .collect()
+    });
+    for rental in active {
+        schedule_revocation(&rental);
+    }
+}
+
+// set or clear the daily rental price of one of the caller's recipes
+#[ic_cdk::update]
+fn set_rental_price(payload: RentalPricePayload) -> Result<Recipe, Error> {
+    let user = caller_user()?;
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    if recipe.user_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the recipe owner can rent out this recipe".to_string(),
+        });
+    }
+    if recipe.is_community {
+        return Err(Error::InvalidPayload {
+            msg: "This is a community recipe, you can not rent it out".to_string(),
+        });
+    }
+    if payload.price_per_day == Some(0) {
+        return Err(Error::InvalidPayload {
+            msg: "Rental prices must be above zero".to_string(),
+        });
+    }
+    let new_recipe = Recipe {
+        rental_price_per_day: payload.price_per_day,
+        ..recipe
+    };
+    RECIPE_STORAGE.with(|s| s.borrow_mut().insert(new_recipe.id, new_recipe.clone()));
+    pages::certify_recipe(new_recipe.id);
+    Ok(new_recipe)
+}
+
+// check that a user may rent a recipe, returning it with the rental fee
+fn check_rental(user_id: u64, payload: &RentPayload) -> Result<(Recipe, u64), Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&payload.recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", payload.recipe_id),
+        })?;
+    let price_per_day = match recipe.rental_price_per_day {
+        Some(price) if !recipe.is_community => price,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: "Sorry, This recipe is not available for rent".to_string(),
+            })
+        }
+    };
+    if recipe.user_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not rent your own recipe".to_string(),
+        });
+    }
+    if active_rental(user_id, recipe.id).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "You are already renting this recipe".to_string(),
+        });
+    }
+    let fee = price_per_day
+        .checked_mul(payload.days as u64)
+        .ok_or_else(|| Error::InvalidPayload {
+            msg: "Rental fee is too large".to_string(),
+        })?;
+    Ok((recipe, fee))
+}
+
+// rent a recipe for a number of days, paying the owner from the caller's
+// balance. Any shortfall is pulled from their ledger account first
+#[ic_cdk::update]
+async fn rent_recipe(payload: RentPayload) -> Result<Rental, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let user = caller_user()?;
+    let (_, fee) = check_rental(user.id, &payload)?;
+
+    if user.balance < fee {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = fee - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the rental, the recipe or balances may have changed during the ledger call
+    let user = caller_user()?;
+    let (recipe, fee) = check_rental(user.id, &payload)?;
+    debit_balance(user.id, fee)?;
+    if let Err(e) = credit_balance(recipe.user_id, fee) {
+        credit_balance(user.id, fee)?;
+        return Err(e);
+    }
+    history::record(
+        TransactionKind::Rental,
+        Some(recipe.id),
+        Some(user.id),
+        Some(recipe.user_id),
+        Some(fee),
+    );
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let now = ic_cdk::api::time();
+    let rental = Rental {
+        id,
+        recipe_id: recipe.id,
+        renter_id: user.id,
+        owner_id: recipe.user_id,
+        fee,
+        start_time: now,
+        end_time: now + payload.days as u64 * DAY,
+        is_active: true,
+    };
+    RENTAL_STORAGE.with(|s| s.borrow_mut().insert(id, rental.clone()));
+    schedule_revocation(&rental);
+    Ok(rental)
+}
+
+// get the rental giving a user access to a recipe, for apps enforcing it
+#[ic_cdk::query]
+fn get_rental(user_id: u64, recipe_id: u64) -> Result<Rental, Error> {
+    active_rental(user_id, recipe_id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "user id:{} is not renting recipe id: {}",
+            user_id, recipe_id
+        ),
+    })
+}
+
+// get the caller's current rentals
+#[ic_cdk::query]
+fn get_my_rentals() -> Result<Vec<Rental>, Error> {
+    let user = caller_user()?;
+    let now = ic_cdk::api::time();
+    Ok(RENTAL_STORAGE.with(|rentals| {
+        rentals
+            .borrow()
+            .iter()
+            .map(|(_, r)| r)
+            .filter(|r| r.renter_id == user.id && r.grants_access(now))
+            .collect()
+    }))
+}
@@ -0,0 +1,400 @@
+use crate::body::{Ingredient, RecipeBody, Step, Temperature, TemperatureScale, Unit};
+use crate::content::{self, RecipePreview};
+use crate::{user_by_principal, Error};
+
+const GRAMS_PER_OUNCE: f64 = 28.3495;
+const GRAMS_PER_POUND: f64 = 453.592;
+const ML_PER_TEASPOON: f64 = 4.92892;
+const ML_PER_TABLESPOON: f64 = 14.7868;
+const ML_PER_FLUID_OUNCE: f64 = 29.5735;
+const ML_PER_CUP: f64 = 236.588;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum UnitSystem {
+    Metric,
+    UsCustomary,
+}
+
+// A recipe's body rescaled to a number of servings, in one unit system
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ScaledRecipe {
+    preview: RecipePreview,
+    servings: u32,
+    unit_system: UnitSystem,
+    body: RecipeBody,
+}
+
+// round to the nearest multiple of 'step'
+fn round_to(value: f64, step: f64) -> f64 {
+    // adding zero turns a negative zero into zero, so "-0.4" shows as "0"
+    (value / step).round() * step + 0.0
+}
+
+// round a quantity to a measure, never rounding anything used down to nothing
+fn kitchen_round(quantity: f64, step: f64) -> f64 {
+    if quantity <= 0.0 {
+        return 0.0;
+    }
+    round_to(quantity, step).max(step)
+}
+
+// a quantity in grams or milliliters, in the units a metric kitchen measures it in
+fn metric(amount: f64, small: Unit, large: Unit) -> (f64, Unit) {
+    if amount >= 1000.0 {
+        return (kitchen_round(amount / 1000.0, 0.05), large);
+    }
+    let step = match amount {
+        a if a < 1.0 => 0.1,
+        a if a < 10.0 => 0.5,
+        a if a < 100.0 => 5.0,
+        _ => 10.0,
+    };
+    (kitchen_round(amount, step), small)
+}
+
+// a weight in grams, in ounces or pounds
+fn us_weight(grams: f64) -> (f64, Unit) {
+    if grams >= GRAMS_PER_POUND {
+        return (kitchen_round(grams / GRAMS_PER_POUND, 0.25), Unit::Pound);
+    }
+    let ounces = grams / GRAMS_PER_OUNCE;
+    let step = if ounces < 4.0 { 0.25 } else { 0.5 };
+    (kitchen_round(ounces, step), Unit::Ounce)
+}
+
+// a volume in milliliters, in the spoon or cup it is measured with
+fn us_volume(ml: f64) -> (f64, Unit) {
+    if ml < ML_PER_TABLESPOON {
+        (kitchen_round(ml / ML_PER_TEASPOON, 0.125), Unit::Teaspoon)
+    } else if ml < ML_PER_CUP / 4.0 {
+        (kitchen_round(ml / ML_PER_TABLESPOON, 0.5), Unit::Tablespoon)
+    } else {
+        (kitchen_round(ml / ML_PER_CUP, 0.25), Unit::Cup)
+    }
+}
+
+pub(crate) fn is_weight(unit: Unit) -> bool {
+    matches!(
+        unit,
+        Unit::Gram | Unit::Kilogram | Unit::Ounce | Unit::Pound
+    )
+}
+
+pub(crate) fn is_counted(unit: Unit) -> bool {
+    matches!(unit, Unit::Pinch | Unit::Piece)
+}
+
+// a quantity in grams for weights and milliliters for volumes, unrounded.
+// Counted units are returned as they are
+pub(crate) fn to_base(quantity: f64, unit: Unit) -> f64 {
+    match unit {
+        Unit::Kilogram | Unit::Liter => quantity * 1000.0,
+        Unit::Ounce => quantity * GRAMS_PER_OUNCE,
+        Unit::Pound => quantity * GRAMS_PER_POUND,
+        Unit::Teaspoon => quantity * ML_PER_TEASPOON,
+        Unit::Tablespoon => quantity * ML_PER_TABLESPOON,
+        Unit::FluidOunce => quantity * ML_PER_FLUID_OUNCE,
+        Unit::Cup => quantity * ML_PER_CUP,
+        Unit::Gram | Unit::Milliliter | Unit::Pinch | Unit::Piece => quantity,
+    }
+}
+
+// convert a quantity to the unit system, rounded to a kitchen measure
+pub(crate) fn convert(quantity: f64, unit: Unit, system: UnitSystem) -> (f64, Unit) {
+    let base = to_base(quantity, unit);
+    match unit {
+        // pinches and pieces are counted
+        unit if is_counted(unit) => (kitchen_round(quantity, 0.5), unit),
+        unit if is_weight(unit) => match system {
+            UnitSystem::Metric => metric(base, Unit::Gram, Unit::Kilogram),
+            UnitSystem::UsCustomary => us_weight(base),
+        },
+        // spoons are measured the same way in both systems
+        Unit::Teaspoon | Unit::Tablespoon => (kitchen_round(quantity, 0.125), unit),
+        _ => match system {
+            UnitSystem::Metric => metric(base, Unit::Milliliter, Unit::Liter),
+            UnitSystem::UsCustomary => us_volume(base),
+        },
+    }
+}
+
+fn convert_temperature(temperature: Temperature, system: UnitSystem) -> Temperature {
+    let degrees = match (temperature.scale, system) {
+        (TemperatureScale::Celsius, UnitSystem::UsCustomary) => {
+            temperature.degrees * 9.0 / 5.0 + 32.0
+        }
+        (TemperatureScale::Fahrenheit, UnitSystem::Metric) => {
+            (temperature.degrees - 32.0) * 5.0 / 9.0
+        }
+        _ => temperature.degrees,
+    };
+    let scale = match system {
+        UnitSystem::Metric => TemperatureScale::Celsius,
+        UnitSystem::UsCustomary => TemperatureScale::Fahrenheit,
+    };
+    // ovens are set in steps of five degrees
+    Temperature {
+        degrees: round_to(degrees, 5.0),
+        scale,
+    }
+}
+
+// the number a text ends with and the index it starts at. A minus sign is
+// part of it, unless it joins the ends of a range such as "20-25°C"
+fn trailing_number(text: &str) -> Option<(usize, f64)> {
+    let mut start = text
+        .rfind(|c: char| !c.is_ascii_digit() && c != '.')
+        .map_or(0, |i| i + 1);
+    if text[..start].ends_with('-') && !text[..start - 1].ends_with(|c: char| c.is_ascii_digit()) {
+        start -= 1;
+    }
+    text[start..].parse().ok().map(|number| (start, number))
+}
+
+// rewrite temperatures written into an instruction, such as "bake at 180°C"
+fn convert_instruction(instruction: &str, system: UnitSystem) -> String {
+    let (from, to) = match system {
+        UnitSystem::Metric => ("°F", "°C"),
+        UnitSystem::UsCustomary => ("°C", "°F"),
+    };
+    let scale = match system {
+        UnitSystem::Metric => TemperatureScale::Fahrenheit,
+        UnitSystem::UsCustomary => TemperatureScale::Celsius,
+    };
+    let convert = |degrees| convert_temperature(Temperature { degrees, scale }, system).degrees;
+    let mut converted = String::new();
+    let mut rest = instruction;
+    while let Some(at) = rest.find(from) {
+        let before = &rest[..at];
+        let number = before.trim_end();
+        match trailing_number(number) {
+            Some((start, high)) => {
+                let head = &number[..start];
+                // both ends of a range such as "20-25°C" are converted
+                match head.strip_suffix('-').and_then(trailing_number) {
+                    Some((low_start, low)) => {
+                        converted.push_str(&head[..low_start]);
+                        converted.push_str(&format!("{}-{}{}", convert(low), convert(high), to));
+                    }
+                    None => {
+                        converted.push_str(head);
+                        converted.push_str(&format!("{}{}", convert(high), to));
+                    }
+                }
+            }
+            None => {
+                converted.push_str(before);
+                converted.push_str(from);
+            }
+        }
+        rest = &rest[at + from.len()..];
+    }
+    converted.push_str(rest);
+    converted
+}
+
+fn scale_ingredient(ingredient: Ingredient, factor: f64, system: UnitSystem) -> Ingredient {
+    let (quantity, unit) = match (ingredient.quantity, ingredient.unit) {
+        (Some(quantity), Some(unit)) => {
+            let (quantity, unit) = convert(quantity * factor, unit, system);
+            (Some(quantity), Some(unit))
+        }
+        (Some(quantity), None) => (Some(kitchen_round(quantity * factor, 0.5)), None),
+        (None, unit) => (None, unit),
+    };
+    Ingredient {
+        quantity,
+        unit,
+        ..ingredient
+    }
+}
+
+fn scale_step(step: Step, system: UnitSystem) -> Step {
+    Step {
+        instruction: convert_instruction(&step.instruction, system),
+        temperature: step.temperature.map(|t| convert_temperature(t, system)),
+        ..step
+    }
+}
+
+// get a recipe's ingredients rescaled to a number of servings and converted to
+// a unit system, only for callers allowed to read the recipe
+#[ic_cdk::query]
+fn get_recipe_scaled(
+    recipe_id: u64,
+    servings: u32,
+    unit_system: UnitSystem,
+) -> Result<ScaledRecipe, Error> {
+    if !(1..=100).contains(&servings) {
+        return Err(Error::InvalidPayload {
+            msg: "Recipes can be scaled to 1 to 100 servings".to_string(),
+        });
+    }
+    let user_id = user_by_principal(ic_cdk::caller()).map(|user| user.id);
+    let recipe = content::viewable_recipe(user_id, recipe_id)?;
+    let base = recipe.servings.ok_or_else(|| Error::InvalidPayload {
+        msg: format!("recipe of id: {} has no servings to scale from", recipe_id),
+    })?;
+    let factor = servings as f64 / base as f64;
+
+    let body = content::body_of(&recipe)?;
+    let body = RecipeBody {
+        ingredients: body
+            .ingredients
+            .into_iter()
+            .map(|i| scale_ingredient(i, factor, unit_system))
+            .collect(),
+        steps: body
+            .steps
+            .into_iter()
+            .map(|s| scale_step(s, unit_system))
+            .collect(),
+        ..body
+    };
+    Ok(ScaledRecipe {
+        preview: recipe.into(),
+        servings,
+        unit_system,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_converts(quantity: f64, unit: Unit, system: UnitSystem, expected: (f64, Unit)) {
+        let (converted, converted_unit) = convert(quantity, unit, system);
+        assert!(
+            (converted - expected.0).abs() < 1e-9,
+            "{} != {}",
+            converted,
+            expected.0
+        );
+        assert!(converted_unit == expected.1);
+    }
+
+    #[test]
+    fn kitchen_round_rounds_to_the_step() {
+        assert_eq!(kitchen_round(1.2, 0.5), 1.0);
+        assert_eq!(kitchen_round(1.3, 0.5), 1.5);
+        assert_eq!(kitchen_round(7.0, 5.0), 5.0);
+    }
+
+    #[test]
+    fn kitchen_round_never_rounds_down_to_nothing() {
+        assert_eq!(kitchen_round(0.1, 0.5), 0.5);
+        assert_eq!(kitchen_round(0.0, 0.5), 0.0);
+        assert_eq!(kitchen_round(-1.0, 0.5), 0.0);
+    }
+
+    #[test]
+    fn convert_weights() {
+        assert_converts(
+            1.0,
+            Unit::Kilogram,
+            UnitSystem::Metric,
+            (1.0, Unit::Kilogram),
+        );
+        assert_converts(
+            1500.0,
+            Unit::Gram,
+            UnitSystem::Metric,
+            (1.5, Unit::Kilogram),
+        );
+        assert_converts(
+            250.0,
+            Unit::Gram,
+            UnitSystem::UsCustomary,
+            (9.0, Unit::Ounce),
+        );
+        assert_converts(
+            500.0,
+            Unit::Gram,
+            UnitSystem::UsCustomary,
+            (1.0, Unit::Pound),
+        );
+        assert_converts(1.0, Unit::Pound, UnitSystem::Metric, (450.0, Unit::Gram));
+    }
+
+    #[test]
+    fn convert_volumes() {
+        assert_converts(
+            2.0,
+            Unit::Cup,
+            UnitSystem::Metric,
+            (470.0, Unit::Milliliter),
+        );
+        assert_converts(
+            100.0,
+            Unit::Milliliter,
+            UnitSystem::UsCustomary,
+            (0.5, Unit::Cup),
+        );
+        assert_converts(
+            10.0,
+            Unit::Milliliter,
+            UnitSystem::UsCustomary,
+            (2.0, Unit::Teaspoon),
+        );
+        assert_converts(
+            1.0,
+            Unit::Tablespoon,
+            UnitSystem::Metric,
+            (1.0, Unit::Tablespoon),
+        );
+    }
+
+    #[test]
+    fn convert_keeps_counted_units() {
+        assert_converts(
+            3.0,
+            Unit::Piece,
+            UnitSystem::UsCustomary,
+            (3.0, Unit::Piece),
+        );
+        assert_converts(0.2, Unit::Pinch, UnitSystem::Metric, (0.5, Unit::Pinch));
+    }
+
+    #[test]
+    fn convert_instruction_reads_signs_and_decimals() {
+        let us = UnitSystem::UsCustomary;
+        assert_eq!(convert_instruction("Freeze at -18°C", us), "Freeze at 0°F");
+        assert_eq!(convert_instruction("Warm to 37.5°C", us), "Warm to 100°F");
+        assert_eq!(
+            convert_instruction("Bake at 180°C for 20 minutes", us),
+            "Bake at 355°F for 20 minutes"
+        );
+    }
+
+    #[test]
+    fn convert_instruction_to_metric() {
+        let metric = UnitSystem::Metric;
+        assert_eq!(
+            convert_instruction("Bake at 350 °F", metric),
+            "Bake at 175°C"
+        );
+        assert_eq!(
+            convert_instruction("Heat the oven", metric),
+            "Heat the oven"
+        );
+        assert_eq!(convert_instruction("Set it to °F", metric), "Set it to °F");
+    }
+
+    #[test]
+    fn convert_instruction_converts_both_ends_of_ranges() {
+        let us = UnitSystem::UsCustomary;
+        assert_eq!(
+            convert_instruction("Proof at 20-25°C", us),
+            "Proof at 70-75°F"
+        );
+        assert_eq!(
+            convert_instruction("Chill to -5-0°C", us),
+            "Chill to 25-30°F"
+        );
+        assert_eq!(
+            convert_instruction("Bake at 350-375°F", UnitSystem::Metric),
+            "Bake at 175-190°C"
+        );
+    }
+}
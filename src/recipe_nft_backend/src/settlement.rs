@@ -0,0 +1,300 @@
+use crate::auction;
+use crate::history::{self, TransactionKind};
+use crate::license::LicenseTier;
+use crate::listing::{self, ListingStatus};
+use crate::pages;
+use crate::{
+    check_size, icrc37, marketplace_config, Contract, Error, Recipe, User, CONTRACT_STORAGE,
+    RECIPE_STORAGE, USER_STORAGE,
+};
+use std::collections::BTreeMap;
+
+// Basis points in a whole, royalties and fees are expressed in basis points
+pub(crate) const BPS_DENOMINATOR: u64 = 10_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Payee {
+    User(u64),
+    Treasury,
+}
+
+// A share of a sale price paid to someone other than the seller
+struct Payout {
+    payee: Payee,
+    amount: u64,
+    kind: TransactionKind,
+}
+
+// A change of recipe ownership, optionally paid for from the new owner's
+// balance. Everything is validated before anything is written, and every
+// balance and ownership mutation is written in the same message.
+pub(crate) struct Settlement {
+    recipe: Recipe,
+    buyer_id: u64,
+    price: u64,
+    kind: TransactionKind,
+    payouts: Vec<Payout>,
+    // the license the new owner gets, the recipe's current license if None
+    license: Option<LicenseTier>,
+}
+
+impl Settlement {
+    // a sale of a recipe to a buyer at a price. The marketplace fee and, on
+    // secondary sales, the creator's royalty are paid out of the price
+    pub(crate) fn purchase(recipe: Recipe, buyer_id: u64, price: u64) -> Self {
+        let mut payouts = vec![];
+        if let Some((creator_id, royalty)) = royalty_for(&recipe, price) {
+            payouts.push(Payout {
+                payee: Payee::User(creator_id),
+                amount: royalty,
+                kind: TransactionKind::Royalty,
+            });
+        }
+        let fee = bps_of(price, marketplace_config().fee_bps);
+        if fee > 0 {
+            payouts.push(Payout {
+                payee: Payee::Treasury,
+                amount: fee,
+                kind: TransactionKind::Fee,
+            });
+        }
+        Settlement {
+            recipe,
+            buyer_id,
+            price,
+            kind: TransactionKind::Sale,
+            payouts,
+            license: None,
+        }
+    }
+
+    // sell the recipe under a specific license
+    pub(crate) fn with_license(self, license: LicenseTier) -> Self {
+        Settlement {
+            license: Some(license),
+            ..self
+        }
+    }
+
+    // a transfer of a recipe to another user with no payment
+    pub(crate) fn transfer(recipe: Recipe, to_id: u64) -> Self {
+        Settlement {
+            recipe,
+            buyer_id: to_id,
+            price: 0,
+            kind: TransactionKind::Transfer,
+            payouts: vec![],
+            license: None,
+        }
+    }
+
+    // apply the settlement and log it, returning its block index
+    pub(crate) fn apply(self) -> Result<u64, Error> {
+        let seller_id = self.recipe.user_id;
+        if seller_id == self.buyer_id {
+            return Err(Error::InvalidPayload {
+                msg: "You can not buy your own recipe".to_string(),
+            });
+        }
+        if auction::is_auctioned(self.recipe.id) {
+            return Err(Error::InvalidPayload {
+                msg: "This recipe is being auctioned".to_string(),
+            });
+        }
+        let paid_out: u64 = self.payouts.iter().map(|payout| payout.amount).sum();
+        if paid_out > self.price {
+            return Err(Error::SettlementFailed {
+                msg: "Payouts exceed the sale price".to_string(),
+            });
+        }
+
+        // validate and compute every mutation up front
+        let mut ids = vec![self.buyer_id, seller_id];
+        ids.extend(self.payouts.iter().filter_map(|payout| match payout.payee {
+            Payee::User(id) => Some(id),
+            Payee::Treasury => None,
+        }));
+        let before = load_users(&ids)?;
+        let mut after = before.clone();
+        let contract_before = CONTRACT_STORAGE.with(|s| s.borrow().get(&0));
+        let mut treasury_after = treasury_balance(&contract_before);
+
+        let buyer = after.get_mut(&self.buyer_id).expect("buyer was loaded");
+        if buyer.balance < self.price {
+            return Err(Error::InvalidPayload {
+                msg: "You do not have enough balance to buy this recipe".to_string(),
+            });
+        }
+        buyer.balance -= self.price;
+        buyer.recipes.push(self.recipe.id);
+
+        for payout in &self.payouts {
+            match payout.payee {
+                Payee::User(id) => {
+                    let payee = after.get_mut(&id).expect("payee was loaded");
+                    credit(payee, payout.amount)?;
+                }
+                Payee::Treasury => {
+                    if contract_before.is_none() {
+                        return Err(Error::NotFound {
+                            msg: "Contract has not been initialized".to_string(),
+                        });
+                    }
+                    treasury_after += payout.amount as u128;
+                }
+            }
+        }
+
+        let seller = after.get_mut(&seller_id).expect("seller was loaded");
+        credit(seller, self.price - paid_out)?;
+        seller.recipes.retain(|r| *r != self.recipe.id);
+
+        let total_before = total_balance(&before) + treasury_balance(&contract_before);
+        if total_before != total_balance(&after) + treasury_after {
+            return Err(Error::SettlementFailed {
+                msg: "Purchase would not conserve total balance".to_string(),
+            });
+        }
+
+        let new_recipe = Recipe {
+            user_id: self.buyer_id,
+            is_for_sale: false,
+            license: self.license.or(self.recipe.license),
+            // rental terms are the seller's, the new owner sets their own
+            rental_price_per_day: None,
+            ..self.recipe.clone()
+        };
+
+        let contract_after = contract_before.clone().map(|contract| Contract {
+            treasury_balance: Some(treasury_after as u64),
+            ..contract
+        });
+
+        // a record past its bound would trap the write, reject it first
+        for user in after.values() {
+            check_size(user, "user")?;
+        }
+        if let Some(contract) = &contract_after {
+            check_size(contract, "contract")?;
+        }
+
+        // nothing awaits between the writes, a trap in any of them undoes them all
+        write(&after, &new_recipe, &contract_after);
+        pages::certify_recipe(self.recipe.id);
+        pages::certify_user(seller_id);
+
+        // approvals and listings of the recipe do not carry over to the new owner
+        icrc37::clear_token_approvals(self.recipe.id);
+        listing::close(
+            self.recipe.id,
+            match self.kind {
+                TransactionKind::Sale => ListingStatus::Sold,
+                _ => ListingStatus::Delisted,
+            },
+        );
+
+        let index = history::record(
+            self.kind,
+            Some(self.recipe.id),
+            Some(seller_id),
+            Some(self.buyer_id),
+            Some(self.price),
+        );
+        for payout in &self.payouts {
+            let to_user_id = match payout.payee {
+                Payee::User(id) => Some(id),
+                Payee::Treasury => None,
+            };
+            history::record(
+                payout.kind,
+                Some(self.recipe.id),
+                Some(self.buyer_id),
+                to_user_id,
+                Some(payout.amount),
+            );
+        }
+        Ok(index)
+    }
+}
+
+// check that a buyer's record has room for one more recipe, before any of
+// their tokens are pulled from the ledger. Recipe ids encode to a fixed size,
+// so any id stands in for the one being bought
+pub(crate) fn check_room(buyer: &User) -> Result<(), Error> {
+    let mut recipes = buyer.recipes.clone();
+    recipes.push(0);
+    check_size(
+        &User {
+            recipes,
+            ..buyer.clone()
+        },
+        "user",
+    )
+}
+
+// The creator and royalty owed to them when a recipe sells at a price. Primary
+// sales by the creator and recipes minted before royalties owe nothing.
+pub(crate) fn royalty_for(recipe: &Recipe, price: u64) -> Option<(u64, u64)> {
+    let creator_id = recipe.creator_id?;
+    let royalty_bps = recipe.royalty_bps.unwrap_or(0);
+    if creator_id == recipe.user_id || royalty_bps == 0 {
+        return None;
+    }
+    Some((creator_id, bps_of(price, royalty_bps)))
+}
+
+// the share of an amount given in basis points, rounded down
+pub(crate) fn bps_of(amount: u64, bps: u16) -> u64 {
+    (amount as u128 * bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+fn treasury_balance(contract: &Option<Contract>) -> u128 {
+    contract
+        .as_ref()
+        .and_then(|contract| contract.treasury_balance)
+        .unwrap_or(0) as u128
+}
+
+fn credit(user: &mut User, amount: u64) -> Result<(), Error> {
+    user.balance = user
+        .balance
+        .checked_add(amount)
+        .ok_or(Error::SettlementFailed {
+            msg: format!("Balance of user id: {} would overflow", user.id),
+        })?;
+    Ok(())
+}
+
+fn load_users(ids: &[u64]) -> Result<BTreeMap<u64, User>, Error> {
+    let mut users = BTreeMap::new();
+    for id in ids {
+        match USER_STORAGE.with(|u| u.borrow().get(id)) {
+            Some(user) => {
+                users.insert(*id, user);
+            }
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("user id:{} does not exist", id),
+                })
+            }
+        }
+    }
+    Ok(users)
+}
+
+fn total_balance(users: &BTreeMap<u64, User>) -> u128 {
+    users.values().map(|user| user.balance as u128).sum()
+}
+
+fn write(users: &BTreeMap<u64, User>, recipe: &Recipe, contract: &Option<Contract>) {
+    USER_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        for user in users.values() {
+            storage.insert(user.id, user.clone());
+        }
+    });
+    RECIPE_STORAGE.with(|s| s.borrow_mut().insert(recipe.id, recipe.clone()));
+    if let Some(contract) = contract {
+        CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, contract.clone()));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::body::{Ingredient, Unit};
+use crate::content;
+use crate::scaling::{self, UnitSystem};
+use crate::{user_by_principal, Error, RECIPE_STORAGE};
+use std::collections::BTreeMap;
+
+const MAX_SHOPPING_LIST_RECIPES: usize = 50;
+
+// Store sections a shopping list is grouped by, in the order a shop is walked
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum Aisle {
+    Produce,
+    MeatAndSeafood,
+    DairyAndEggs,
+    Bakery,
+    Pantry,
+    Spices,
+    Frozen,
+    Beverages,
+    Other,
+}
+
+// keywords an ingredient's aisle is guessed from
+const AISLE_KEYWORDS: [(&str, Aisle); 66] = [
+    ("onion", Aisle::Produce),
+    ("garlic", Aisle::Produce),
+    ("tomato", Aisle::Produce),
+    ("potato", Aisle::Produce),
+    ("carrot", Aisle::Produce),
+    ("lettuce", Aisle::Produce),
+    ("spinach", Aisle::Produce),
+    ("bell pepper", Aisle::Produce),
+    ("lemon", Aisle::Produce),
+    ("lime", Aisle::Produce),
+    ("apple", Aisle::Produce),
+    ("banana", Aisle::Produce),
+    ("basil", Aisle::Produce),
+    ("parsley", Aisle::Produce),
+    ("cilantro", Aisle::Produce),
+    ("ginger", Aisle::Produce),
+    ("mushroom", Aisle::Produce),
+    ("celery", Aisle::Produce),
+    ("cucumber", Aisle::Produce),
+    ("avocado", Aisle::Produce),
+    ("chicken", Aisle::MeatAndSeafood),
+    ("beef", Aisle::MeatAndSeafood),
+    ("pork", Aisle::MeatAndSeafood),
+    ("lamb", Aisle::MeatAndSeafood),
+    ("bacon", Aisle::MeatAndSeafood),
+    ("sausage", Aisle::MeatAndSeafood),
+    ("turkey", Aisle::MeatAndSeafood),
+    ("fish", Aisle::MeatAndSeafood),
+    ("salmon", Aisle::MeatAndSeafood),
+    ("tuna", Aisle::MeatAndSeafood),
+    ("shrimp", Aisle::MeatAndSeafood),
+    ("milk", Aisle::DairyAndEggs),
+    ("cream", Aisle::DairyAndEggs),
+    ("butter", Aisle::DairyAndEggs),
+    ("cheese", Aisle::DairyAndEggs),
+    ("yogurt", Aisle::DairyAndEggs),
+    ("egg", Aisle::DairyAndEggs),
+    ("bread", Aisle::Bakery),
+    ("tortilla", Aisle::Bakery),
+    ("pita", Aisle::Bakery),
+    ("flour", Aisle::Pantry),
+    ("sugar", Aisle::Pantry),
+    ("rice", Aisle::Pantry),
+    ("pasta", Aisle::Pantry),
+    ("oil", Aisle::Pantry),
+    ("vinegar", Aisle::Pantry),
+    ("honey", Aisle::Pantry),
+    ("oats", Aisle::Pantry),
+    ("beans", Aisle::Pantry),
+    ("stock", Aisle::Pantry),
+    ("sauce", Aisle::Pantry),
+    ("yeast", Aisle::Pantry),
+    ("salt", Aisle::Spices),
+    ("pepper", Aisle::Spices),
+    ("cumin", Aisle::Spices),
+    ("paprika", Aisle::Spices),
+    ("cinnamon", Aisle::Spices),
+    ("oregano", Aisle::Spices),
+    ("powder", Aisle::Spices),
+    ("vanilla", Aisle::Spices),
+    ("frozen", Aisle::Frozen),
+    ("ice cream", Aisle::Frozen),
+    ("wine", Aisle::Beverages),
+    ("juice", Aisle::Beverages),
+    ("coffee", Aisle::Beverages),
+    ("water", Aisle::Beverages),
+];
+
+impl Aisle {
+    // guess the aisle of an ingredient from the longest keyword in its name,
+    // so "bell pepper" is produce and "garlic powder" a spice
+    fn of(name: &str) -> Aisle {
+        let name = name.to_lowercase();
+        AISLE_KEYWORDS
+            .iter()
+            .filter(|(keyword, _)| name.contains(keyword))
+            .max_by_key(|(keyword, _)| keyword.len())
+            .map_or(Aisle::Other, |(_, aisle)| *aisle)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ShoppingItem {
+    name: String,
+    // None for ingredients bought to taste
+    quantity: Option<f64>,
+    unit: Option<Unit>,
+    recipe_ids: Vec<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AisleItems {
+    aisle: Aisle,
+    items: Vec<ShoppingItem>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ShoppingList {
+    aisles: Vec<AisleItems>,
+    // recipes left out because they do not exist or the caller can not read them
+    skipped_recipe_ids: Vec<u64>,
+}
+
+// What quantities of an ingredient are added up in. Weights and volumes are
+// summed in grams and milliliters, other units are only summed with themselves
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Measure {
+    Weight,
+    Volume,
+    Counted(Unit),
+    Each,
+    ToTaste,
+}
+
+// an ingredient's quantity in the base unit of its measure
+fn measure(ingredient: &Ingredient, factor: f64) -> (Measure, f64) {
+    let Some(quantity) = ingredient.quantity.map(|q| q * factor) else {
+        return (Measure::ToTaste, 0.0);
+    };
+    match ingredient.unit {
+        Some(unit) if scaling::is_counted(unit) => (Measure::Counted(unit), quantity),
+        Some(unit) if scaling::is_weight(unit) => {
+            (Measure::Weight, scaling::to_base(quantity, unit))
+        }
+        Some(unit) => (Measure::Volume, scaling::to_base(quantity, unit)),
+        None => (Measure::Each, quantity),
+    }
+}
+
+// the total of a measure as it is written on the list
+fn shopping_quantity(measure: Measure, total: f64) -> (Option<f64>, Option<Unit>) {
+    let (quantity, unit) = match measure {
+        Measure::Weight => scaling::convert(total, Unit::Gram, UnitSystem::Metric),
+        Measure::Volume => scaling::convert(total, Unit::Milliliter, UnitSystem::Metric),
+        Measure::Counted(unit) => (total.ceil(), unit),
+        // whole items are bought, not halves
+        Measure::Each => return (Some(total.ceil()), None),
+        Measure::ToTaste => return (None, None),
+    };
+    (Some(quantity), Some(unit))
+}
+
+// add up the ingredients of several recipes, each scaled to
+// 'servings_per_recipe', into a list grouped by store aisle. Recipes the
+// caller can not read are skipped
+#[ic_cdk::query]
+fn build_shopping_list(
+    recipe_ids: Vec<u64>,
+    servings_per_recipe: u32,
+) -> Result<ShoppingList, Error> {
+    if recipe_ids.is_empty() || recipe_ids.len() > MAX_SHOPPING_LIST_RECIPES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Shopping lists are built from 1 to {} recipes",
+                MAX_SHOPPING_LIST_RECIPES
+            ),
+        });
+    }
+    if !(1..=100).contains(&servings_per_recipe) {
+        return Err(Error::InvalidPayload {
+            msg: "Recipes can be scaled to 1 to 100 servings".to_string(),
+        });
+    }
+    let user_id = user_by_principal(ic_cdk::caller()).map(|user| user.id);
+
+    // totals by ingredient name and measure, with the recipes using them
+    let mut totals: BTreeMap<(String, Measure), (String, f64, Vec<u64>)> = BTreeMap::new();
+    let mut skipped_recipe_ids = vec![];
+    for recipe_id in recipe_ids {
+        let recipe = match RECIPE_STORAGE.with(|s| s.borrow().get(&recipe_id)) {
+            Some(recipe) if content::can_view(user_id, &recipe) => recipe,
+            _ => {
+                skipped_recipe_ids.push(recipe_id);
+                continue;
+            }
+        };
+        // recipes without servings are bought for as written
+        let factor = recipe
+            .servings
+            .map_or(1.0, |base| servings_per_recipe as f64 / base as f64);
+        for ingredient in content::body_of(&recipe)?.ingredients {
+            let (measure, quantity) = measure(&ingredient, factor);
+            let name = ingredient.name.trim().to_string();
+            let entry = totals
+                .entry((name.to_lowercase(), measure))
+                .or_insert_with(|| (name, 0.0, vec![]));
+            entry.1 += quantity;
+            if !entry.2.contains(&recipe_id) {
+                entry.2.push(recipe_id);
+            }
+        }
+    }
+
+    let mut by_aisle: BTreeMap<Aisle, Vec<ShoppingItem>> = BTreeMap::new();
+    for ((_, measure), (name, total, recipe_ids)) in totals {
+        let (quantity, unit) = shopping_quantity(measure, total);
+        by_aisle
+            .entry(Aisle::of(&name))
+            .or_default()
+            .push(ShoppingItem {
+                name,
+                quantity,
+                unit,
+                recipe_ids,
+            });
+    }
+    Ok(ShoppingList {
+        aisles: by_aisle
+            .into_iter()
+            .map(|(aisle, items)| AisleItems { aisle, items })
+            .collect(),
+        skipped_recipe_ids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingredient(quantity: Option<f64>, unit: Option<Unit>) -> Ingredient {
+        Ingredient {
+            name: "flour".to_string(),
+            quantity,
+            unit,
+            notes: None,
+        }
+    }
+
+    fn assert_measures(ingredient: Ingredient, factor: f64, expected: (Measure, f64)) {
+        let (measure, quantity) = measure(&ingredient, factor);
+        assert!(measure == expected.0);
+        assert!(
+            (quantity - expected.1).abs() < 1e-9,
+            "{} != {}",
+            quantity,
+            expected.1
+        );
+    }
+
+    #[test]
+    fn measure_sums_weights_in_grams() {
+        let kilograms = ingredient(Some(2.0), Some(Unit::Kilogram));
+        assert_measures(kilograms, 1.5, (Measure::Weight, 3000.0));
+        let ounces = ingredient(Some(1.0), Some(Unit::Ounce));
+        assert_measures(ounces, 1.0, (Measure::Weight, 28.3495));
+    }
+
+    #[test]
+    fn measure_sums_volumes_in_milliliters() {
+        let cups = ingredient(Some(1.0), Some(Unit::Cup));
+        assert_measures(cups, 2.0, (Measure::Volume, 473.176));
+        let liters = ingredient(Some(0.5), Some(Unit::Liter));
+        assert_measures(liters, 1.0, (Measure::Volume, 500.0));
+    }
+
+    #[test]
+    fn measure_keeps_counted_and_unitless_quantities() {
+        let pieces = ingredient(Some(3.0), Some(Unit::Piece));
+        assert_measures(pieces, 0.5, (Measure::Counted(Unit::Piece), 1.5));
+        let eggs = ingredient(Some(2.0), None);
+        assert_measures(eggs, 2.0, (Measure::Each, 4.0));
+        let salt = ingredient(None, Some(Unit::Pinch));
+        assert_measures(salt, 3.0, (Measure::ToTaste, 0.0));
+    }
+
+    #[test]
+    fn shopping_quantity_rounds_up_whole_items() {
+        let (quantity, unit) = shopping_quantity(Measure::Each, 1.5);
+        assert_eq!(quantity, Some(2.0));
+        assert!(unit.is_none());
+        let (quantity, unit) = shopping_quantity(Measure::Counted(Unit::Pinch), 0.5);
+        assert_eq!(quantity, Some(1.0));
+        assert!(unit == Some(Unit::Pinch));
+        let (quantity, unit) = shopping_quantity(Measure::Weight, 1500.0);
+        assert_eq!(quantity, Some(1.5));
+        assert!(unit == Some(Unit::Kilogram));
+        let (quantity, unit) = shopping_quantity(Measure::ToTaste, 0.0);
+        assert!(quantity.is_none() && unit.is_none());
+    }
+}
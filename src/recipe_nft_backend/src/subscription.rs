@@ -0,0 +1,401 @@
+use crate::history::{self, TransactionKind};
+use crate::{
+    caller_user, credit_balance, debit_balance, fund_user, ledger, Error, ID_COUNTER, PLAN_STORAGE,
+    RECIPE_STORAGE, SUBSCRIPTION_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::{borrow::Cow, time::Duration};
+use validator::Validate;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// subscriptions are billed every 30 days
+const BILLING_PERIOD: u64 = 30 * DAY;
+// how long access survives failed renewals, retried daily until then
+const GRACE_PERIOD: u64 = 3 * DAY;
+const RETRY_INTERVAL: u64 = DAY;
+
+// A monthly plan giving subscribers access to every recipe its creator owns
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SubscriptionPlan {
+    id: u64,
+    creator_id: u64,
+    name: String,
+    price_per_period: u64,
+    // inactive plans take no new subscribers and are not renewed
+    is_active: bool,
+}
+
+impl Storable for SubscriptionPlan {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SubscriptionPlan {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum SubscriptionStatus {
+    Active,
+    // the last renewal failed, access continues until 'grace_until'
+    PastDue,
+    // no further renewals, access continues until 'paid_until'
+    Cancelled,
+    Lapsed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64,
+    plan_id: u64,
+    subscriber_id: u64,
+    creator_id: u64,
+    started_at: u64,
+    paid_until: u64,
+    grace_until: Option<u64>,
+    status: SubscriptionStatus,
+}
+
+impl Subscription {
+    fn grants_access(&self, now: u64) -> bool {
+        match self.status {
+            SubscriptionStatus::Active | SubscriptionStatus::Cancelled => now < self.paid_until,
+            SubscriptionStatus::PastDue => self.grace_until.is_some_and(|until| now < until),
+            SubscriptionStatus::Lapsed => false,
+        }
+    }
+}
+
+impl Storable for Subscription {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct PlanPayload {
+    #[validate(length(min = 3, max = 100))]
+    name: String,
+    #[validate(range(min = 1))]
+    price_per_period: u64,
+}
+
+fn next_id() -> u64 {
+    ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids")
+}
+
+fn get_plan(plan_id: u64) -> Result<SubscriptionPlan, Error> {
+    PLAN_STORAGE
+        .with(|s| s.borrow().get(&plan_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("subscription plan of id: {} not found", plan_id),
+        })
+}
+
+fn store(subscription: &Subscription) {
+    SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().insert(subscription.id, subscription.clone()));
+}
+
+// the subscription giving a user access to a creator's recipes right now, if any
+pub(crate) fn active_subscription(user_id: u64, creator_id: u64) -> Option<Subscription> {
+    let now = ic_cdk::api::time();
+    SUBSCRIPTION_STORAGE.with(|subscriptions| {
+        subscriptions.borrow().iter().map(|(_, s)| s).find(|s| {
+            s.subscriber_id == user_id && s.creator_id == creator_id && s.grants_access(now)
+        })
+    })
+}
+
+// pay a creator one period of a subscription from the subscriber's balance
+fn charge(subscription: &Subscription, amount: u64) -> Result<(), Error> {
+    debit_balance(subscription.subscriber_id, amount)?;
+    if let Err(e) = credit_balance(subscription.creator_id, amount) {
+        credit_balance(subscription.subscriber_id, amount)?;
+        return Err(e);
+    }
+    history::record(
+        TransactionKind::Subscription,
+        None,
+        Some(subscription.subscriber_id),
+        Some(subscription.creator_id),
+        Some(amount),
+    );
+    Ok(())
+}
+
+// bill the subscription at a point in time
+fn schedule_billing(subscription_id: u64, at: u64) {
+    let delay = at.saturating_sub(ic_cdk::api::time());
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay), move || bill(subscription_id));
+}
+
+// renew a subscription whose period has ended. A failed renewal leaves it past
+// due and is retried daily until the grace period ends, when it lapses
+fn bill(subscription_id: u64) {
+    let subscription = match SUBSCRIPTION_STORAGE.with(|s| s.borrow().get(&subscription_id)) {
+        Some(subscription) => subscription,
+        None => return,
+    };
+    let now = ic_cdk::api::time();
+    match subscription.status {
+        SubscriptionStatus::Active if now < subscription.paid_until => {
+            return schedule_billing(subscription.id, subscription.paid_until)
+        }
+        SubscriptionStatus::Active | SubscriptionStatus::PastDue => {}
+        SubscriptionStatus::Cancelled | SubscriptionStatus::Lapsed => return,
+    }
+
+    let plan = match get_plan(subscription.plan_id) {
+        Ok(plan) if plan.is_active => plan,
+        _ => {
+            return store(&Subscription {
+                status: SubscriptionStatus::Cancelled,
+                ..subscription
+            })
+        }
+    };
+
+    if charge(&subscription, plan.price_per_period).is_ok() {
+        // late renewals keep the billing cycle, the grace period is shorter than a period
+        let paid_until = subscription.paid_until + BILLING_PERIOD;
+        store(&Subscription {
+            paid_until,
+            grace_until: None,
+            status: SubscriptionStatus::Active,
+            ..subscription
+        });
+        return schedule_billing(subscription_id, paid_until);
+    }
+
+    let grace_until = subscription
+        .grace_until
+        .unwrap_or(subscription.paid_until + GRACE_PERIOD);
+    if now >= grace_until {
+        return store(&Subscription {
+            status: SubscriptionStatus::Lapsed,
+            ..subscription
+        });
+    }
+    store(&Subscription {
+        grace_until: Some(grace_until),
+        status: SubscriptionStatus::PastDue,
+        ..subscription
+    });
+    schedule_billing(subscription_id, (now + RETRY_INTERVAL).min(grace_until));
+}
+
+// timers do not survive upgrades, reschedule billing of every live subscription
+pub(crate) fn reschedule_billing() {
+    let now = ic_cdk::api::time();
+    let live: Vec<Subscription> = SUBSCRIPTION_STORAGE.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| {
+                matches!(
+                    s.status,
+                    SubscriptionStatus::Active | SubscriptionStatus::PastDue
+                )
+            })
+            .collect()
+    });
+    for subscription in live {
+        let at = match subscription.status {
+            SubscriptionStatus::PastDue => now,
+            _ => subscription.paid_until,
+        };
+        schedule_billing(subscription.id, at);
+    }
+}
+
+// create a monthly subscription plan for the caller's recipes
+#[ic_cdk::update]
+fn create_subscription_plan(payload: PlanPayload) -> Result<SubscriptionPlan, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let creator = caller_user()?;
+    let plan = SubscriptionPlan {
+        id: next_id(),
+        creator_id: creator.id,
+        name: payload.name,
+        price_per_period: payload.price_per_period,
+        is_active: true,
+    };
+    PLAN_STORAGE.with(|s| s.borrow_mut().insert(plan.id, plan.clone()));
+    Ok(plan)
+}
+
+// stop one of the caller's plans. Current subscribers keep access until the
+// end of the period they paid for
+#[ic_cdk::update]
+fn deactivate_subscription_plan(plan_id: u64) -> Result<SubscriptionPlan, Error> {
+    let creator = caller_user()?;
+    let plan = get_plan(plan_id)?;
+    if plan.creator_id != creator.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the creator can change this plan".to_string(),
+        });
+    }
+    let plan = SubscriptionPlan {
+        is_active: false,
+        ..plan
+    };
+    PLAN_STORAGE.with(|s| s.borrow_mut().insert(plan.id, plan.clone()));
+    Ok(plan)
+}
+
+// check that a user may subscribe to a plan
+fn check_subscription(user_id: u64, plan_id: u64) -> Result<SubscriptionPlan, Error> {
+    let plan = get_plan(plan_id)?;
+    if !plan.is_active {
+        return Err(Error::InvalidPayload {
+            msg: "This plan no longer takes subscribers".to_string(),
+        });
+    }
+    if plan.creator_id == user_id {
+        return Err(Error::InvalidPayload {
+            msg: "You can not subscribe to your own plan".to_string(),
+        });
+    }
+    if active_subscription(user_id, plan.creator_id).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "You are already subscribed to this creator".to_string(),
+        });
+    }
+    Ok(plan)
+}
+
+// subscribe to a plan, paying the first period from the caller's balance. Any
+// shortfall is pulled from their ledger account first, later periods are
+// billed from the marketplace balance
+#[ic_cdk::update]
+async fn subscribe(plan_id: u64) -> Result<Subscription, Error> {
+    let user = caller_user()?;
+    let plan = check_subscription(user.id, plan_id)?;
+
+    if user.balance < plan.price_per_period {
+        let ledger = ledger::ledger_canister()?;
+        let shortfall = plan.price_per_period - user.balance;
+        ledger::transfer_from(ledger, user.principal, shortfall).await?;
+        fund_user(user.id, shortfall)?;
+    }
+
+    // re-check the subscription, the plan or balances may have changed during the ledger call
+    let user = caller_user()?;
+    let plan = check_subscription(user.id, plan_id)?;
+    let now = ic_cdk::api::time();
+    let subscription = Subscription {
+        id: next_id(),
+        plan_id,
+        subscriber_id: user.id,
+        creator_id: plan.creator_id,
+        started_at: now,
+        paid_until: now + BILLING_PERIOD,
+        grace_until: None,
+        status: SubscriptionStatus::Active,
+    };
+    charge(&subscription, plan.price_per_period)?;
+    store(&subscription);
+    schedule_billing(subscription.id, subscription.paid_until);
+    Ok(subscription)
+}
+
+// cancel the caller's subscription, access continues until the end of the
+// period already paid for
+#[ic_cdk::update]
+fn cancel_subscription(subscription_id: u64) -> Result<Subscription, Error> {
+    let user = caller_user()?;
+    let subscription = SUBSCRIPTION_STORAGE
+        .with(|s| s.borrow().get(&subscription_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("subscription of id: {} not found", subscription_id),
+        })?;
+    if subscription.subscriber_id != user.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the subscriber can cancel this subscription".to_string(),
+        });
+    }
+    let status = match subscription.status {
+        SubscriptionStatus::Active => SubscriptionStatus::Cancelled,
+        SubscriptionStatus::PastDue => SubscriptionStatus::Lapsed,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: "This subscription has already ended".to_string(),
+            })
+        }
+    };
+    let subscription = Subscription {
+        status,
+        ..subscription
+    };
+    store(&subscription);
+    Ok(subscription)
+}
+
+// get the subscription giving a user access to a recipe, for apps enforcing it
+#[ic_cdk::query]
+fn get_subscription_access(user_id: u64, recipe_id: u64) -> Result<Subscription, Error> {
+    let recipe = RECIPE_STORAGE
+        .with(|s| s.borrow().get(&recipe_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("recipe of id: {} not found", recipe_id),
+        })?;
+    active_subscription(user_id, recipe.user_id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "user id:{} is not subscribed to the owner of recipe id: {}",
+            user_id, recipe_id
+        ),
+    })
+}
+
+// get the plans a creator offers
+#[ic_cdk::query]
+fn get_creator_plans(creator_id: u64) -> Vec<SubscriptionPlan> {
+    PLAN_STORAGE.with(|plans| {
+        plans
+            .borrow()
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.creator_id == creator_id && p.is_active)
+            .collect()
+    })
+}
+
+// get every subscription of the caller
+#[ic_cdk::query]
+fn get_my_subscriptions() -> Result<Vec<Subscription>, Error> {
+    let user = caller_user()?;
+    Ok(SUBSCRIPTION_STORAGE.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| s.subscriber_id == user.id)
+            .collect()
+    }))
+}